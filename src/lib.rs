@@ -1,5 +1,18 @@
 use scrypto::prelude::*;
 
+// Maximum length of the message a sponsor can attach to a donation
+const MAX_DONATION_MESSAGE_LENGTH: usize = 256;
+
+// Maximum number of reward tokens; each of them makes accruing dividends more expensive
+const MAX_REWARD_TOKENS: usize = 10;
+
+// Maximum number of claim NFTs kept at the same time; no unstake starts when they are this many
+const MAX_CLAIM_NFTS: u32 = 100;
+
+// Maximum number of seconds between two recurring buys (one year); it keeps the schedule far
+// from the i64 overflow
+const MAX_DCA_INTERVAL: i64 = 31_536_000;
+
 /* NonFungibleData of the badge needed to deposit 401k in a Vault.
  * A badge is minted by the buy method, it must be burned by the post_sell
  * method.
@@ -12,6 +25,36 @@ struct DepositBadge {
     price: Decimal,
     // Amount of dividends paid in the current buy operation
     dividends_amount: Decimal,
+    // Amount of XRD paid in the current buy operation, fees included
+    xrd_amount: Decimal,
+    // Amount of XRD added to the jackpot in the current buy operation
+    jackpot_amount: Decimal,
+}
+
+/* NonFungibleData of the receipt of a buy limit order.
+ * It is minted by the place_limit_order method and burned by the cancel_limit_order method.
+ */
+#[derive(ScryptoSbor, NonFungibleData)]
+struct LimitOrderReceipt {
+    // The account the 401k are sent to
+    account: Global<Account>,
+    // Amount of escrowed XRD when the order was placed
+    xrd_amount: Decimal,
+    // The order is executed when the spot price is at or below this price
+    max_price: Decimal,
+}
+
+/* NonFungibleData of the receipt of a recurring buy (DCA) subscription.
+ * It is minted by the subscribe_dca method and burned by the cancel_dca method.
+ */
+#[derive(ScryptoSbor, NonFungibleData)]
+struct DcaReceipt {
+    // The account the 401k are sent to
+    account: Global<Account>,
+    // Amount of XRD to use for each buy, fees included
+    amount_per_buy: Decimal,
+    // Seconds between two buys
+    interval: i64,
 }
 
 /* NonFungibleData of the badge needed to withdraw 401k from a Vault.
@@ -21,26 +64,371 @@ struct DepositBadge {
 struct WithdrawBadge {
 }
 
+/* NonFungibleData of the claim NFT a validator returns when unstaking; it mirrors the validator
+ * blueprint one, that Scrypto doesn't export.
+ */
+#[derive(ScryptoSbor, NonFungibleData)]
+struct UnstakeData {
+    // Name of the claim NFT
+    name: String,
+    // Epoch from which the unstaked XRD can be claimed
+    claim_epoch: Epoch,
+    // Amount of XRD to claim
+    claim_amount: Decimal,
+}
+
 // Internal representation of a user; an item of the buyers KVS.
 #[derive(ScryptoSbor, Clone)]
 struct User {
     // How many 401k this user owns
     current_bought_amount: Decimal,
     // Weighted average dividends per 401k at buy time for this user
-    dividends_per_401k: Decimal,
+    dividends_per_401k: PreciseDecimal,
     // Dividends accrued to this user because of the 401k he sold
     accrued_dividends: Decimal,
     // Next jackpot to accrue to this user
     current_jackpot_number: u32,
     // Past jackpots amount accrued to this user
     accrued_jackpot: Decimal,
+    // Amount of 401k stored in the AccountLocker for this user (included in
+    // current_bought_amount)
+    locked_amount: Decimal,
+    // Vesting schedules of the airdrops received by this user and not fully vested yet
+    vestings: Vec<Vesting>,
+    // Lifetime statistics of this user
+    stats: UserStats,
+    // Accounting of the reward tokens for this user; a missing entry means nothing accrued and
+    // no reward distributed since the user last accrued
+    rewards: IndexMap<ResourceAddress, UserReward>,
+    // 401k left in the account by a sell that closed a dust position (not included in
+    // current_bought_amount); they earn no dividends, jackpots or rewards until the user buys,
+    // sells, transfers or migrates again
+    closed_dust_amount: Decimal,
+    // XRD owed to this user by sells or jackpot withdrawals that couldn't be fully paid with
+    // liquid XRD; they are paid by withdraw_dividends once their unstaking completes
+    pending_xrd_amount: Decimal,
+}
+
+// Accounting of a reward token for a user
+#[derive(ScryptoSbor, Clone)]
+struct UserReward {
+    // Rewards per 401k at the last accrual for this user
+    reward_per_401k: PreciseDecimal,
+    // Rewards accrued to this user and not withdrawn yet
+    accrued_amount: Decimal,
+}
+
+// Lifetime statistics of a user
+#[derive(ScryptoSbor, Clone)]
+struct UserStats {
+    // Total amount of 401k bought or received (airdrops and transfers included)
+    total_bought_amount: Decimal,
+    // Total amount of 401k sold
+    total_sold_amount: Decimal,
+    // Total XRD spent buying 401k, fees included
+    total_xrd_spent: Decimal,
+    // Total XRD received selling 401k, fees excluded
+    total_xrd_received: Decimal,
+    // Total XRD paid as dividends and jackpot fees on buys, sells and transfers
+    total_fees_paid: Decimal,
+    // Total dividends withdrawn
+    total_dividends_withdrawn: Decimal,
+    // Total jackpot shares withdrawn
+    total_jackpot_withdrawn: Decimal,
+    // When the user got 401k for the first time (seconds since unix epoch, 0 if never)
+    first_buy_at: i64,
+    // When the user last bought, sold, received, transferred or withdrew anything (seconds
+    // since unix epoch)
+    last_activity_at: i64,
+    // Weighted average XRD cost of the owned 401k, fees included; airdropped and transferred
+    // 401k count as zero cost
+    average_cost: Decimal,
+    // Profit or loss realized by selling 401k: XRD received minus the average cost of the sold
+    // 401k
+    realized_pnl: Decimal,
+}
+
+// A vesting schedule of airdropped 401k assigned to a user
+#[derive(ScryptoSbor, Clone)]
+struct Vesting {
+    // Amount of airdropped 401k subject to this schedule
+    amount: Decimal,
+    // When the airdrop or the airdrop campaign happened (seconds since unix epoch)
+    start: i64,
+    // Seconds after start when the 401k start vesting
+    cliff: i64,
+    // Seconds after start when all of the 401k are vested
+    duration: i64,
+}
+
+// Vesting schedule to apply to the allocations of an airdrop or an airdrop campaign
+#[derive(ScryptoSbor, Clone)]
+pub struct VestingSchedule {
+    // Seconds after the airdrop when the 401k start vesting
+    cliff: i64,
+    // Seconds after the airdrop when all of the 401k are vested; they vest linearly from the
+    // airdrop time, but nothing can be sold before the cliff
+    duration: i64,
+}
+
+// A component allowed to hold 401k; an item of the component_holders KVS
+#[derive(ScryptoSbor, Clone)]
+struct ComponentHolder {
+    // Whether the component is allowed to buy 401k
+    allowed: bool,
+    // Accounting of the 401k held by the component
+    holder: User,
+}
+
+// The opening batch auction
+#[derive(ScryptoSbor)]
+struct Auction {
+    // When the commitment window closes (seconds since unix epoch)
+    ends_at: i64,
+    // XRD committed; after the auction is closed, the unfilled XRD not refunded yet
+    xrd: Vault,
+    // Total XRD committed
+    total_committed: Decimal,
+    // XRD committed by the accounts that haven't claimed yet
+    unclaimed_committed: Decimal,
+    // Whether the auction has been closed
+    closed: bool,
+    // The 401k bought at the close and not claimed yet
+    coins: FungibleVault,
+    // Clearing price (dividends and jackpot excluded)
+    price: Decimal,
+    // XRD paid as dividends and jackpot fees at the close
+    fees_amount: Decimal,
+    // Accounting of the unclaimed 401k; they earn dividends and jackpots that are passed to the
+    // buyers when they claim
+    holder: User,
+}
+
+// Kind of operation recorded in the history of an account
+#[derive(ScryptoSbor, Clone)]
+enum HistoryOperation {
+    // 401k bought
+    Buy,
+    // 401k sold
+    Sell,
+    // 401k received from an airdrop or an airdrop campaign
+    Airdrop,
+    // 401k sent to another account
+    TransferSent,
+    // 401k received from another account
+    TransferReceived,
+    // Dividends withdrawn
+    DividendsWithdrawal,
+    // Jackpot shares withdrawn
+    JackpotWithdrawal,
+    // Position migrated from another account; its history stays in the source account
+    Migration(Global<Account>),
+}
+
+// Key of the history KVS: the account and the sequence number of the operation
+type HistoryKey = (Global<Account>, u64);
+
+// An operation in the history of an account; an item of the history KVS
+#[derive(ScryptoSbor, Clone)]
+pub struct HistoryEntry {
+    // Kind of operation
+    operation: HistoryOperation,
+    // Amount of 401k bought, sold, received or sent (zero for withdrawals)
+    amount: Decimal,
+    // Amount of XRD paid (fees included), received (fees excluded) or withdrawn; for transfers
+    // it is the fee paid by the sender
+    xrd_amount: Decimal,
+    // Price of the 401k (zero for transfers and withdrawals)
+    price: Decimal,
+    // When the operation happened (seconds since unix epoch)
+    timestamp: i64,
+}
+
+// A fungible token distributed pro rata to the 401k holders; an item of the reward_tokens KVS
+#[derive(ScryptoSbor)]
+struct RewardToken {
+    // The rewards not withdrawn yet
+    vault: Vault,
+    // Rewards distributed per 401k coin so far
+    reward_per_401k: PreciseDecimal,
+    // Divisibility of the token; only whole units can be withdrawn
+    divisibility: u8,
+    // Rewards distributed to holders and not withdrawn yet
+    liability: Decimal,
+    // Rounding residue of the accrued and withdrawn rewards; it stays in the vault
+    dust: PreciseDecimal,
+}
+
+// A buy limit order; an item of the limit_orders KVS
+#[derive(ScryptoSbor)]
+struct LimitOrder {
+    // The account the 401k are sent to
+    account: Global<Account>,
+    // The escrowed XRD; an empty vault means the order has been executed or cancelled
+    xrd: Vault,
+    // The order is executed when the spot price is at or below this price
+    max_price: Decimal,
+}
+
+// A recurring buy (DCA) subscription; an item of the dca_subscriptions KVS
+#[derive(ScryptoSbor)]
+struct DcaSubscription {
+    // The account the 401k are sent to
+    account: Global<Account>,
+    // The XRD budget not spent yet
+    xrd: Vault,
+    // Amount of XRD to use for each buy, fees included
+    amount_per_buy: Decimal,
+    // Seconds between two buys
+    interval: i64,
+    // When the next buy is due (seconds since unix epoch)
+    next_buy_at: i64,
+    // Whether the subscription has been cancelled
+    cancelled: bool,
 }
 
 // Information about a past jackpot; an item of the jackpots KVS
 #[derive(ScryptoSbor)]
 struct Jackpot {
     // XRD prize per 401k coin
-    prize_per_401k: Decimal,
+    prize_per_401k: PreciseDecimal,
+}
+
+// An airdrop campaign; an item of the airdrop_campaigns KVS
+#[derive(ScryptoSbor)]
+struct AirdropCampaign {
+    // The 401k bought for this campaign and not claimed yet
+    coins: FungibleVault,
+    // Accounting of the unclaimed 401k; they earn dividends and jackpots that are passed to the
+    // recipients when they claim their allocation
+    holder: User,
+    // When the campaign expires; after that the unclaimed 401k can be burned
+    expires_at: i64,
+    // Price the 401k were bought at
+    price: Decimal,
+    // When the campaign was created; the vesting schedule starts from here
+    created_at: i64,
+    // Optional vesting schedule applied to the claimed 401k
+    vesting: Option<VestingSchedule>,
+}
+
+// The 401k allocated to an account by an airdrop campaign; the airdrop_allocations KVS contains
+// a list of them for each account
+#[derive(ScryptoSbor, Clone)]
+struct AirdropAllocation {
+    // Id of the airdrop campaign
+    campaign_id: u32,
+    // Amount of 401k allocated to the account
+    amount: Decimal,
+}
+
+// Price of the 401k during the presale
+#[derive(ScryptoSbor)]
+enum PresalePrice {
+    // Fixed XRD price per 401k coin
+    Fixed(Decimal),
+    // Discount on the bonding curve price (0-1 range)
+    Discount(Decimal),
+}
+
+// Settings of the optional presale that precedes the bonding curve opening
+#[derive(ScryptoSbor)]
+pub struct Presale {
+    // When the presale ends and the bonding curve opens (seconds since unix epoch)
+    ends_at: i64,
+    // Price of the 401k during the presale
+    price: PresalePrice,
+}
+
+// XRD of a vault that are staked with the validator or being unstaked
+#[derive(ScryptoSbor, Clone)]
+struct StakedXrd {
+    // XRD currently staked
+    staked: Decimal,
+    // XRD being unstaked
+    unstaking: Decimal,
+}
+
+// Reward token balances reported by the audit method
+#[derive(ScryptoSbor)]
+struct RewardTokenAudit {
+    // Reward tokens in the vault
+    vault_amount: Decimal,
+    // Rewards distributed to holders and not withdrawn yet
+    liability: Decimal,
+    // Vault amount minus liability; a negative value is a shortfall
+    surplus: Decimal,
+    // Rounding residue of the accrued and withdrawn rewards
+    dust: PreciseDecimal,
+}
+
+// Where a donation goes
+#[derive(ScryptoSbor, PartialEq)]
+enum DonationTarget {
+    Dividends,
+    Jackpot,
+    Rewards(ResourceAddress),
+}
+
+// Report returned by the audit method: vault balances against the liabilities towards holders
+#[derive(ScryptoSbor)]
+pub struct AuditReport {
+    // XRD in the dividends vault
+    dividends_vault_amount: Decimal,
+    // Dividends distributed to holders and not withdrawn yet
+    dividends_liability: Decimal,
+    // Dividends vault amount minus dividends liability; a negative value is a shortfall
+    dividends_surplus: Decimal,
+    // XRD in the jackpot vault, staked and unstaking ones included
+    jackpot_vault_amount: Decimal,
+    // Jackpot shares distributed to holders and not withdrawn yet
+    jackpot_liability: Decimal,
+    // Amount of the next jackpot, not distributed yet
+    current_jackpot_amount: Decimal,
+    // Jackpot vault amount minus jackpot liability and current jackpot amount; a negative value
+    // is a shortfall
+    jackpot_surplus: Decimal,
+    // Maximum shortfall that is tolerated when withdrawing dividends or jackpot shares
+    dust_tolerance: Decimal,
+    // Dividends rounding residue that can be swept
+    dividends_dust: PreciseDecimal,
+    // Jackpot rounding residue that can be swept
+    jackpot_dust: PreciseDecimal,
+    // XRD in the payouts vault, staked and unstaking ones included
+    payouts_vault_amount: Decimal,
+    // XRD owed to users by sells or jackpot withdrawals that couldn't be fully paid with liquid
+    // XRD
+    pending_xrd_liability: Decimal,
+    // Balances of each reward token
+    reward_tokens: IndexMap<ResourceAddress, RewardTokenAudit>,
+}
+
+// This event is emitted when a jackpot can't be distributed because too few 401k exist; the
+// jackpot is kept for the next cycle
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct JackpotRolledOverEvent {
+    // Amount of the jackpot rolled over to the next cycle
+    jackpot_amount: Decimal,
+    // 401k supply at the time of the rollover
+    current_supply: Decimal,
+}
+
+// This event is emitted when a buy is partially filled because it would exceed the supply cap
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct SupplyCapReachedEvent {
+    // Maximum 401k supply that can be reached by buying
+    supply_cap: Decimal,
+    // Amount of XRD refunded to the buyer
+    refunded_xrd_amount: Decimal,
+}
+
+// This event is emitted when the owner sweeps the dividends and jackpot rounding residue
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct DustSweptEvent {
+    // Amount of XRD swept from the dividends vault
+    dividends_amount: Decimal,
+    // Amount of XRD swept from the jackpot vault
+    jackpot_amount: Decimal,
 }
 
 // This event is emitted when a user buys some 401k.
@@ -55,7 +443,28 @@ struct BuyEvent {
     // Current amount of the next jackpot
     current_jackpot_amount: Decimal,
     // Dividends accrued per 401k so far
-    global_dividends_per_401k: Decimal,
+    global_dividends_per_401k: PreciseDecimal,
+    // 401k ATH since the last jackpot distribution
+    ath: Decimal,
+    // Amount of dividends accued to the buyer
+    buyer_total_accrued_dividends: Decimal,
+    // Past jackpots amount accrued to the buyer
+    buyer_accrued_jackpot: Decimal,
+}
+
+// This event is emitted when an allowlisted component buys some 401k.
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct ComponentBuyEvent {
+    // The component the 401k have been deposited in
+    component_address: ComponentAddress,
+    // Price the 401k were bought at
+    price: Decimal,
+    // Amount of 401k bought
+    bought_amount: Decimal,
+    // Current amount of the next jackpot
+    current_jackpot_amount: Decimal,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
     // 401k ATH since the last jackpot distribution
     ath: Decimal,
     // Amount of dividends accued to the buyer
@@ -76,11 +485,44 @@ struct SellEvent {
     // Current amount of the next jackpot
     current_jackpot_amount: Decimal,
     // Dividends accrued per 401k so far
-    global_dividends_per_401k: Decimal,
+    global_dividends_per_401k: PreciseDecimal,
     // Amount of dividends accued to the seller
     seller_total_accrued_dividends: Decimal,
     // Past jackpots amount accrued to the seller
     seller_accrued_jackpot: Decimal,
+    // Profit or loss realized by this sale
+    realized_pnl: Decimal,
+    // Profit or loss realized by all of the sales of the seller so far
+    seller_total_realized_pnl: Decimal,
+    // Part of the sale XRD that couldn't be paid with liquid XRD; it will be paid by
+    // withdrawing dividends once its unstaking completes
+    deferred_xrd_amount: Decimal,
+}
+
+// This event is emitted when an allowlisted component sells some 401k.
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct ComponentSellEvent {
+    // The component the 401k have been withdrawn from
+    component_address: ComponentAddress,
+    // Price the 401k were sold at
+    price: Decimal,
+    // Amount of 401k sold
+    sold_amount: Decimal,
+    // Current amount of the next jackpot
+    current_jackpot_amount: Decimal,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+    // Amount of dividends accued to the seller
+    seller_total_accrued_dividends: Decimal,
+    // Past jackpots amount accrued to the seller
+    seller_accrued_jackpot: Decimal,
+    // Profit or loss realized by this sale
+    realized_pnl: Decimal,
+    // Profit or loss realized by all of the sales of the seller so far
+    seller_total_realized_pnl: Decimal,
+    // Part of the sale XRD that couldn't be paid with liquid XRD; it will be paid by
+    // withdrawing dividends once its unstaking completes
+    deferred_xrd_amount: Decimal,
 }
 
 // This event is emitted when a user withdraws his dividends and jackpot share
@@ -90,8 +532,27 @@ struct WithdrawDividendsEvent {
     account: Global<Account>,
     // Amount of withdrawn dividends
     withdrawn_dividends: Decimal,
-    // Amount of withdrawn jackpot
+    // Amount of withdrawn jackpot; the part that wasn't liquid has been added to the pending XRD
+    withdrawn_jackpot: Decimal,
+    // Amount of withdrawn rewards for each reward token
+    withdrawn_rewards: IndexMap<ResourceAddress, Decimal>,
+    // Amount of pending XRD from past sells or jackpot withdrawals paid in this withdrawal
+    withdrawn_pending_xrd: Decimal,
+}
+
+// This event is emitted when an allowlisted component withdraws its dividends and jackpot share
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct ComponentWithdrawDividendsEvent {
+    // The component who withdrew dividends
+    component_address: ComponentAddress,
+    // Amount of withdrawn dividends
+    withdrawn_dividends: Decimal,
+    // Amount of withdrawn jackpot; the part that wasn't liquid has been added to the pending XRD
     withdrawn_jackpot: Decimal,
+    // Amount of withdrawn rewards for each reward token
+    withdrawn_rewards: IndexMap<ResourceAddress, Decimal>,
+    // Amount of pending XRD from past sells or jackpot withdrawals paid in this withdrawal
+    withdrawn_pending_xrd: Decimal,
 }
 
 // This event is emitted when a jackpot is distributed and a new jackpot cycle begins
@@ -100,13 +561,308 @@ struct JackpotDistributedEvent {
     // Amount to distribute
     jackpot_amount: Decimal,
     // Amount per 401k to distribute
-    prize_per_401k: Decimal,
+    prize_per_401k: PreciseDecimal,
 }
 
 #[derive(ScryptoSbor, ScryptoEvent)]
 struct AirdropCompletedEvent {
     // Dividends accrued per 401k so far
-    global_dividends_per_401k: Decimal,
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// This event is emitted when the 401k can't be deposited in the account of an airdrop recipient
+// and are stored in the AccountLocker instead
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AirdropRecipientFailedEvent {
+    // The account that refused the deposit
+    account: Global<Account>,
+    // Share of the airdrop assigned to this account
+    share: Decimal,
+    // Amount of 401k stored in the AccountLocker
+    amount: Decimal,
+}
+
+// This event is emitted when a user moves his position from one account to another
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct PositionMigratedEvent {
+    // The account the position has been moved from
+    from_account: Global<Account>,
+    // The account the position has been moved to
+    to_account: Global<Account>,
+    // Amount of 401k moved
+    migrated_amount: Decimal,
+    // Amount of 401k owned by the destination account after the migration
+    to_account_amount: Decimal,
+    // Amount of dividends accued to the destination account
+    to_account_total_accrued_dividends: Decimal,
+    // Past jackpots amount accrued to the destination account
+    to_account_accrued_jackpot: Decimal,
+}
+
+// This event is emitted when a user transfers some 401k to another user
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct TransferEvent {
+    // The sender account
+    from_account: Global<Account>,
+    // The recipient account
+    to_account: Global<Account>,
+    // Amount of 401k sent, transfer fee included
+    sent_amount: Decimal,
+    // Amount of 401k received
+    received_amount: Decimal,
+    // Current amount of the next jackpot
+    current_jackpot_amount: Decimal,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// This event is emitted when an airdrop campaign is created
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AirdropCampaignCreatedEvent {
+    // Id of the new campaign
+    campaign_id: u32,
+    // Price the 401k were bought at
+    price: Decimal,
+    // Amount of 401k bought for the campaign
+    bought_amount: Decimal,
+    // When the campaign expires
+    expires_at: i64,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// This event is emitted when a user claims his allocation in an airdrop campaign
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AirdropClaimedEvent {
+    // Id of the campaign
+    campaign_id: u32,
+    // The account the 401k have been deposited in
+    account: Global<Account>,
+    // Amount of claimed 401k
+    amount: Decimal,
+}
+
+// This event is emitted when an account commits XRD to the opening batch auction
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AuctionCommittedEvent {
+    // The committing account
+    account: Global<Account>,
+    // Amount of XRD committed in this operation
+    amount: Decimal,
+    // Total XRD committed by all of the accounts
+    total_committed: Decimal,
+}
+
+// This event is emitted when the opening batch auction is closed
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AuctionClosedEvent {
+    // Clearing price (dividends and jackpot excluded)
+    price: Decimal,
+    // Amount of 401k bought
+    bought_amount: Decimal,
+    // Amount of XRD used, fees included
+    filled_xrd_amount: Decimal,
+    // Amount of XRD exceeding the supply cap, they are refunded to the committers
+    unfilled_xrd_amount: Decimal,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// This event is emitted when an account claims its 401k from the opening batch auction
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AuctionClaimedEvent {
+    // The claiming account
+    account: Global<Account>,
+    // Amount of 401k claimed
+    amount: Decimal,
+    // Amount of unfilled XRD refunded
+    refunded_xrd_amount: Decimal,
+}
+
+// This event is emitted when the club graduates to a TwoResourcePool
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct GraduatedEvent {
+    // Address of the created TwoResourcePool
+    pool_address: ComponentAddress,
+    // Amount of XRD moved from the bonding curve pool to the TwoResourcePool
+    xrd_amount: Decimal,
+    // Amount of 401k minted for the TwoResourcePool
+    coin_amount: Decimal,
+    // Bonding curve spot price the 401k were minted at
+    price: Decimal,
+    // Amount of bonding curve pool XRD moved to the treasury
+    treasury_xrd_amount: Decimal,
+    // Amount of pool units locked in the component
+    pool_units_amount: Decimal,
+}
+
+// This event is emitted when the XRD staked with the validator are rebalanced
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct StakingRebalancedEvent {
+    // Amount of pool and jackpot XRD staked in this operation
+    staked_amount: Decimal,
+    // Amount of XRD whose unstaking started in this operation: pool and jackpot XRD exceeding
+    // the staking percentage, pending payouts and dividends
+    unstaked_amount: Decimal,
+    // Amount of staking yield harvested in this operation; it will be added to the dividends
+    // when its unstaking completes
+    harvested_amount: Decimal,
+    // Loss in the redemption value of the LSUs (validator slashing) taken from the staked XRD
+    // in this operation
+    slashed_amount: Decimal,
+    // Amount of unstaked XRD claimed from the validator in this operation
+    claimed_amount: Decimal,
+    // Amount of pool and jackpot XRD currently staked
+    total_staked_amount: Decimal,
+}
+
+// This event is emitted when a sponsor donates XRD to the dividends or to the jackpot
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct DonationEvent {
+    // Where the donation went
+    target: DonationTarget,
+    // Amount of donated XRD or reward tokens
+    amount: Decimal,
+    // The account of the sponsor, if he wants to be known
+    sponsor: Option<Global<Account>>,
+    // A message from the sponsor
+    message: Option<String>,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+    // Amount of the next jackpot
+    current_jackpot_amount: Decimal,
+}
+
+// This event is emitted when XRD are used to buy back and burn 401k
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct BuybackEvent {
+    // Amount of XRD used, fees included
+    xrd_amount: Decimal,
+    // Whether the XRD came from the treasury vault
+    from_treasury: bool,
+    // Amount of bought and burned 401k
+    burned_amount: Decimal,
+    // Price the 401k were bought at
+    price: Decimal,
+    // Amount of XRD paid as dividends
+    dividends_amount: Decimal,
+    // Amount of XRD added to the jackpot
+    jackpot_amount: Decimal,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// This event is emitted when a buy limit order is placed
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct LimitOrderPlacedEvent {
+    // Id of the order
+    order_id: u64,
+    // The account the 401k will be sent to
+    account: Global<Account>,
+    // Amount of escrowed XRD
+    xrd_amount: Decimal,
+    // The order is executed when the spot price is at or below this price
+    max_price: Decimal,
+}
+
+// This event is emitted when a buy limit order is executed
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct LimitOrderExecutedEvent {
+    // Id of the order
+    order_id: u64,
+    // The account the 401k have been sent to
+    account: Global<Account>,
+    // Amount of XRD used, fees included
+    xrd_amount: Decimal,
+    // Amount of bought 401k
+    bought_amount: Decimal,
+    // Price the 401k were bought at
+    price: Decimal,
+    // Amount of XRD still escrowed because of the supply cap or of the order price
+    remaining_xrd_amount: Decimal,
+}
+
+// This event is emitted when a buy limit order is cancelled
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct LimitOrderCancelledEvent {
+    // Id of the order
+    order_id: u64,
+    // Amount of refunded XRD
+    refunded_xrd_amount: Decimal,
+}
+
+// This event is emitted when a recurring buy (DCA) subscription is created
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct DcaSubscribedEvent {
+    // Id of the subscription
+    subscription_id: u64,
+    // The account the 401k will be sent to
+    account: Global<Account>,
+    // XRD budget of the subscription
+    xrd_amount: Decimal,
+    // Amount of XRD to use for each buy, fees included
+    amount_per_buy: Decimal,
+    // Seconds between two buys
+    interval: i64,
+}
+
+// This event is emitted when the XRD budget of a recurring buy (DCA) subscription is topped up
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct DcaToppedUpEvent {
+    // Id of the subscription
+    subscription_id: u64,
+    // Amount of added XRD
+    xrd_amount: Decimal,
+    // XRD budget of the subscription not spent yet
+    remaining_xrd_amount: Decimal,
+}
+
+// This event is emitted when a recurring buy (DCA) is executed
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct DcaExecutedEvent {
+    // Id of the subscription
+    subscription_id: u64,
+    // The account the 401k have been sent to
+    account: Global<Account>,
+    // Amount of XRD used, fees included
+    xrd_amount: Decimal,
+    // Amount of bought 401k
+    bought_amount: Decimal,
+    // Price the 401k were bought at
+    price: Decimal,
+    // XRD budget of the subscription not spent yet
+    remaining_xrd_amount: Decimal,
+    // When the next buy is due (seconds since unix epoch)
+    next_buy_at: i64,
+}
+
+// This event is emitted when a recurring buy (DCA) subscription is cancelled
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct DcaCancelledEvent {
+    // Id of the subscription
+    subscription_id: u64,
+    // Amount of refunded XRD
+    refunded_xrd_amount: Decimal,
+}
+
+// This event is emitted when an expired airdrop campaign is closed
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct AirdropCampaignClosedEvent {
+    // Id of the campaign
+    campaign_id: u32,
+    // Amount of unclaimed 401k that have been burned
+    burned_amount: Decimal,
+    // Dividends accrued per 401k so far
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// This event is emitted when a user claims the 401k stored for him in the AccountLocker
+#[derive(ScryptoSbor, ScryptoEvent)]
+struct LockedCoinsClaimedEvent {
+    // The account the 401k have been deposited in
+    account: Global<Account>,
+    // Amount of claimed 401k
+    amount: Decimal,
 }
 
 #[blueprint]
@@ -116,14 +872,55 @@ struct AirdropCompletedEvent {
     Global<Account>,
     User,
     u32,
+    u64,
     Jackpot,
+    AirdropCampaign,
+    Vec<AirdropAllocation>,
+    ComponentAddress,
+    ComponentHolder,
+    HistoryKey,
+    HistoryEntry,
+    Decimal,
+    ResourceAddress,
+    RewardToken,
+    LimitOrderReceipt,
+    LimitOrder,
+    DcaReceipt,
+    DcaSubscription,
 )]
 #[events(
     BuyEvent,
     SellEvent,
     WithdrawDividendsEvent,
+    ComponentBuyEvent,
+    ComponentSellEvent,
+    ComponentWithdrawDividendsEvent,
     JackpotDistributedEvent,
     AirdropCompletedEvent,
+    AirdropRecipientFailedEvent,
+    LockedCoinsClaimedEvent,
+    PositionMigratedEvent,
+    TransferEvent,
+    AirdropCampaignCreatedEvent,
+    AirdropClaimedEvent,
+    AirdropCampaignClosedEvent,
+    DustSweptEvent,
+    JackpotRolledOverEvent,
+    SupplyCapReachedEvent,
+    AuctionCommittedEvent,
+    AuctionClosedEvent,
+    AuctionClaimedEvent,
+    GraduatedEvent,
+    StakingRebalancedEvent,
+    DonationEvent,
+    BuybackEvent,
+    LimitOrderPlacedEvent,
+    LimitOrderExecutedEvent,
+    LimitOrderCancelledEvent,
+    DcaSubscribedEvent,
+    DcaToppedUpEvent,
+    DcaExecutedEvent,
+    DcaCancelledEvent,
 )]
 mod club401k {
     enable_method_auth! {
@@ -134,6 +931,15 @@ mod club401k {
             seller => updatable_by: [OWNER];
             staker => updatable_by: [OWNER];
             airdropper => updatable_by: [OWNER];
+            claimer => updatable_by: [OWNER];
+            donor => updatable_by: [OWNER];
+            // This role is deny_all; the owner can change it to enable transfers among users
+            transferrer => updatable_by: [OWNER];
+            // This role is deny_all; the owner can assign it to whoever runs the buybacks
+            treasurer => updatable_by: [OWNER];
+            // This role is allow_all so that anybody can execute the limit orders whose price has
+            // been reached; the owner can restrict it to trusted keepers
+            keeper => updatable_by: [OWNER];
         },
         methods {
             buy => restrict_to: [buyer];
@@ -141,14 +947,126 @@ mod club401k {
             pre_sell => restrict_to: [seller];
             sell => restrict_to: [seller];
             withdraw_dividends => restrict_to: [staker];
+            post_buy_component => restrict_to: [buyer];
+            sell_component => restrict_to: [seller];
+            withdraw_component_dividends => restrict_to: [staker];
             airdrop => restrict_to: [airdropper];
+            claim_locked_coins => restrict_to: [claimer];
+            migrate_position => restrict_to: [seller];
+            transfer => restrict_to: [transferrer];
+            create_airdrop_campaign => restrict_to: [airdropper, OWNER];
+            close_airdrop_campaign => restrict_to: [airdropper, OWNER];
+            claim_airdrop => restrict_to: [claimer];
+            open_auction => restrict_to: [OWNER];
+            commit_to_auction => restrict_to: [buyer];
+            close_auction => restrict_to: [buyer];
+            claim_auction => restrict_to: [claimer];
+            graduate => restrict_to: [OWNER];
+            update_graduation_settings => restrict_to: [OWNER];
+            update_staking_settings => restrict_to: [OWNER];
+            rebalance_stake => restrict_to: [staker];
+            donate_dividends => restrict_to: [donor];
+            donate_jackpot => restrict_to: [donor];
+            donate_rewards => restrict_to: [donor];
+            add_reward_token => restrict_to: [OWNER];
+            deposit_treasury => restrict_to: [treasurer, OWNER];
+            withdraw_treasury => restrict_to: [OWNER];
+            buyback => restrict_to: [treasurer, OWNER];
+            buyback_from_treasury => restrict_to: [treasurer, OWNER];
+            place_limit_order => restrict_to: [buyer];
+            execute_orders => restrict_to: [keeper];
+            cancel_limit_order => PUBLIC;
+            subscribe_dca => restrict_to: [buyer];
+            top_up_dca => restrict_to: [buyer];
+            run_dca => PUBLIC;
+            cancel_dca => PUBLIC;
             update_settings => restrict_to: [OWNER];
+            update_transfer_settings => restrict_to: [OWNER];
+            add_component_holder => restrict_to: [OWNER];
+            remove_component_holder => restrict_to: [OWNER];
+            update_dust_tolerance => restrict_to: [OWNER];
+            sweep_dust => restrict_to: [OWNER];
+            update_min_jackpot_supply => restrict_to: [OWNER];
+            update_supply_cap => restrict_to: [OWNER];
+            update_trade_limits => restrict_to: [OWNER];
+            update_presale_allowlist => restrict_to: [OWNER];
+            deposit_presale_rebates => restrict_to: [OWNER];
+            withdraw_presale_rebates => restrict_to: [OWNER];
+            update_anti_sniper_fee => restrict_to: [OWNER];
+            list_users => PUBLIC;
+            get_history => PUBLIC;
+            audit => PUBLIC;
         }
     }
 
     struct Club401k {
         // Maximum 401k supply
         max_supply: Decimal,
+        // Maximum 401k supply that can be reached by buying; it must be lower than max_supply
+        // because the price grows without limit approaching it
+        supply_cap: Decimal,
+        // Minimum amount of XRD per buy; it also applies to limit orders and DCA buys, not to
+        // airdrops, airdrop campaigns, auction commitments and buybacks
+        min_buy_xrd_amount: Decimal,
+        // Minimum amount of 401k per sell, unless the whole position is sold
+        min_sell_amount: Decimal,
+        // A position smaller than this is considered dust
+        dust_threshold: Decimal,
+        // Whether the dust position left by a sell is closed: the dust stays in the account but
+        // stops earning dividends, jackpots and rewards, that go to the other holders
+        close_dust_positions: bool,
+        // Total 401k in closed dust positions
+        closed_dust_supply: Decimal,
+        // Settings of the presale, if any
+        presale: Option<Presale>,
+        // When buying and selling 401k is allowed (seconds since unix epoch)
+        trading_starts_at: i64,
+        // Additional percentage of XRD to pay to the jackpot when trading starts (0-1 range); it
+        // decays linearly to zero over anti_sniper_minutes
+        anti_sniper_fee: Decimal,
+        // How many minutes after the trading start the anti-sniper fee lasts
+        anti_sniper_minutes: i64,
+        // XRD amount (fees included) each allowlisted account can still spend during the presale
+        presale_allowlist: KeyValueStore<Global<Account>, Decimal>,
+        // XRD funded by the owner to give back to the presale buyers the difference between the
+        // bonding curve price and the presale one
+        presale_rebates: Vault,
+        // The opening batch auction, if any
+        auction: Option<Auction>,
+        // XRD committed to the opening batch auction by each account that hasn't claimed yet
+        auction_commitments: KeyValueStore<Global<Account>, Decimal>,
+        // The club can graduate when the bonding curve pool contains this amount of XRD (zero
+        // means only when the 401k supply reaches curve_change_supply)
+        graduation_xrd_amount: Decimal,
+        // Percentage of the bonding curve pool XRD moved to the TwoResourcePool at graduation
+        // (0-1 range, zero disables graduation); the rest goes to the treasury
+        graduation_xrd_percentage: Decimal,
+        // The TwoResourcePool created at graduation; all of the trades happen on it afterwards
+        graduation_pool: Option<Global<TwoResourcePool>>,
+        // The pool units received at graduation; they are locked forever
+        pool_units: Option<FungibleVault>,
+        // The validator to stake part of the pool and jackpot XRD with
+        validator: Option<Global<Validator>>,
+        // Maximum percentage of the pool and jackpot XRD to stake (0-1 range)
+        staking_percentage: Decimal,
+        // The LSUs received by staking
+        lsus: Option<FungibleVault>,
+        // The claim NFTs received by unstaking
+        claim_nfts: Option<NonFungibleVault>,
+        // Pool XRD staked or being unstaked
+        pool_stake: StakedXrd,
+        // Jackpot XRD staked or being unstaked
+        jackpot_stake: StakedXrd,
+        // XRD owed to users staked or being unstaked; they go to the payouts vault when claimed
+        payout_stake: StakedXrd,
+        // Staking yield and dividends staked or being unstaked; they are distributed when
+        // claimed
+        dividends_stake: StakedXrd,
+        // Unstaked XRD owed to users by sells or jackpot withdrawals
+        payouts: Vault,
+        // XRD owed to users by sells or jackpot withdrawals that couldn't be fully paid with
+        // liquid XRD
+        pending_xrd_liability: Decimal,
         // Amount of XRD that are initialy added to the pool when calculating price
         fake_initial_xrd: PreciseDecimal,
         // 401k supply at which the bonding curve changes
@@ -175,11 +1093,23 @@ mod club401k {
         next_badge_id: u64,
         // Collection of users
         users: KeyValueStore<Global<Account>, User>,
+        // Index of the registered accounts, so that users can be enumerated
+        user_index: KeyValueStore<u64, Global<Account>>,
+        // Number of registered accounts
+        holders_count: u64,
+        // Number of registered accounts currently owning some 401k
+        active_holders_count: u64,
+        // Collection of components allowed to hold 401k
+        component_holders: KeyValueStore<ComponentAddress, ComponentHolder>,
+        // History of the operations of each account, indexed by account and sequence number
+        history: KeyValueStore<HistoryKey, HistoryEntry>,
+        // Number of operations in the history of each account
+        history_lengths: KeyValueStore<Global<Account>, u64>,
         // Last transaction a deposit or withdraw badge was issued. Use this to avoid issuing both
         // deposit and withdraw badges in the same transaction
         transaction_hash: Hash,
         // Current dividends amount per 401k coin
-        dividends_per_401k: Decimal,
+        dividends_per_401k: PreciseDecimal,
         // 401k ATH since the last jackpot distribution
         ath: Decimal,
         // A jackpot distribution can happen when the price is below this percentage of the ATH
@@ -194,6 +1124,53 @@ mod club401k {
         current_jackpot_amount: Decimal,
         // Collection of past jackpots
         jackpots: KeyValueStore<u32, Jackpot>,
+        // The jackpot is rolled over to the next cycle instead of being distributed if the 401k
+        // supply is zero or below this amount
+        min_jackpot_supply: Decimal,
+        // Dividends distributed to holders and not withdrawn yet
+        dividends_liability: Decimal,
+        // Jackpot shares distributed to holders and not withdrawn yet
+        jackpot_liability: Decimal,
+        // Maximum shortfall of the dividends or jackpot vault that is tolerated when withdrawing;
+        // within this tolerance withdrawals are reduced to the available amount
+        dust_tolerance: Decimal,
+        // Dividends that no holder can withdraw because of rounding; they are part of the
+        // dividends liability until the owner sweeps them
+        dividends_dust: PreciseDecimal,
+        // Jackpot shares that no holder can withdraw because of rounding; they are part of the
+        // jackpot liability until the owner sweeps them
+        jackpot_dust: PreciseDecimal,
+        // The fungible tokens distributed pro rata to the 401k holders besides the XRD dividends
+        reward_tokens: KeyValueStore<ResourceAddress, RewardToken>,
+        // The addresses of the reward tokens, in the order they were added
+        reward_token_addresses: Vec<ResourceAddress>,
+        // XRD set aside to buy back and burn 401k
+        treasury: Vault,
+        // ResourceManager for the limit order receipts
+        order_receipt_manager: NonFungibleResourceManager,
+        // Buy limit orders by id
+        limit_orders: KeyValueStore<u64, LimitOrder>,
+        // Id of the next limit order
+        next_order_id: u64,
+        // ResourceManager for the recurring buy (DCA) subscription receipts
+        dca_receipt_manager: NonFungibleResourceManager,
+        // Recurring buy (DCA) subscriptions by id
+        dca_subscriptions: KeyValueStore<u64, DcaSubscription>,
+        // Id of the next recurring buy (DCA) subscription
+        next_subscription_id: u64,
+        // Percentage of the transferred 401k to pay as dividends when transferring (0-1 range)
+        transfer_dividends_percentage: Decimal,
+        // Percentage of the transferred 401k to pay to the jackpot when transferring (0-1 range)
+        transfer_jackpot_percentage: Decimal,
+        // Where the airdropped 401k that couldn't be deposited are stored until the recipient
+        // claims them
+        account_locker: Global<AccountLocker>,
+        // Id of the next airdrop campaign
+        next_campaign_id: u32,
+        // Collection of airdrop campaigns
+        airdrop_campaigns: KeyValueStore<u32, AirdropCampaign>,
+        // The airdrop campaign allocations not claimed yet by each account
+        airdrop_allocations: KeyValueStore<Global<Account>, Vec<AirdropAllocation>>,
     }
 
     impl Club401k {
@@ -221,6 +1198,12 @@ mod club401k {
             jackpot_threshold: Decimal,
             // How long must the price stay below the threshold for the jackpot to be distributed
             jackpot_threshold_time: i64,
+            // Optional presale, only allowlisted accounts can buy during it
+            presale: Option<Presale>,
+            // XRD to fund the presale rebates; required when there is a presale
+            presale_rebates_bucket: Option<Bucket>,
+            // When buying and selling 401k is allowed, presale excluded (seconds since unix epoch)
+            trading_starts_at: i64,
         ) -> (
             // Globalized 401k component
             Global<Club401k>,
@@ -268,6 +1251,39 @@ mod club401k {
                 jackpot_threshold_time >= 0,
                 "Wrong jackpot_threshold_time"
             );
+            if let Some(ref presale_rebates_bucket) = presale_rebates_bucket {
+                assert!(
+                    presale_rebates_bucket.resource_address() == XRD,
+                    "Wrong coin"
+                );
+            }
+            if let Some(ref presale) = presale {
+                assert!(
+                    presale.ends_at > Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch,
+                    "Wrong presale ends_at"
+                );
+                assert!(
+                    trading_starts_at >= presale.ends_at,
+                    "Trading can't start before the presale ends"
+                );
+                assert!(
+                    match presale_rebates_bucket {
+                        None => false,
+                        Some(ref bucket) => bucket.amount() > Decimal::ZERO,
+                    },
+                    "Presale rebates not funded"
+                );
+                match presale.price {
+                    PresalePrice::Fixed(price) => assert!(
+                        price > Decimal::ZERO,
+                        "Wrong presale price"
+                    ),
+                    PresalePrice::Discount(discount) => assert!(
+                        discount >= Decimal::ZERO && discount < Decimal::ONE,
+                        "Wrong presale discount"
+                    ),
+                }
+            }
 
             // Reserve a componet address; it will be used to set roles in the created resources
             let (address_reservation, component_address) =
@@ -369,24 +1385,137 @@ mod club401k {
             ))
             .create_with_no_initial_supply();
 
-            // Instantiate the component and globalize it
-            let component = Self {
-                max_supply: max_supply,
-                fake_initial_xrd: (initial_price * max_supply).into(),
-                curve_change_supply: curve_change_supply_percentage * max_supply,
-                price_amplifier: price_amplifier,
-                pool: Vault::new(XRD),
-                dividends: Vault::new(XRD),
-                dividends_percentage: dividends_percentage,
-                jackpot: Vault::new(XRD),
-                jackpot_percentage: jackpot_percentage,
-                deposit_badge_manager: deposit_badge_manager,
-                withdraw_badge_manager: withdraw_badge_manager,
-                coin_manager: coin_manager,
-                next_badge_id: 1,
-                users: KeyValueStore::new_with_registered_type(),
+            // Create the limit order receipt resource; users can cancel their orders by returning
+            // the receipts
+            let order_receipt_manager = ResourceBuilder::new_integer_non_fungible_with_registered_type::<LimitOrderReceipt>(
+                OwnerRole::None
+            )
+            .metadata(metadata!(
+                roles {
+                    metadata_setter => rule!(require(owner_badge_address));
+                    metadata_setter_updater => rule!(require(owner_badge_address));
+                    metadata_locker => rule!(require(owner_badge_address));
+                    metadata_locker_updater => rule!(require(owner_badge_address));
+                },
+                init {
+                    "name" => "401k limit order", updatable;
+                }
+            ))
+            .mint_roles(mint_roles!(
+                minter => rule!(require(global_caller(component_address)));
+                minter_updater => rule!(deny_all);
+            ))
+            .burn_roles(burn_roles!(
+                burner => rule!(require(global_caller(component_address)));
+                burner_updater => rule!(deny_all);
+            ))
+            .create_with_no_initial_supply();
+
+            // Create the recurring buy (DCA) subscription receipt resource; users can cancel their
+            // subscriptions by returning the receipts
+            let dca_receipt_manager = ResourceBuilder::new_integer_non_fungible_with_registered_type::<DcaReceipt>(
+                OwnerRole::None
+            )
+            .metadata(metadata!(
+                roles {
+                    metadata_setter => rule!(require(owner_badge_address));
+                    metadata_setter_updater => rule!(require(owner_badge_address));
+                    metadata_locker => rule!(require(owner_badge_address));
+                    metadata_locker_updater => rule!(require(owner_badge_address));
+                },
+                init {
+                    "name" => "401k DCA subscription", updatable;
+                }
+            ))
+            .mint_roles(mint_roles!(
+                minter => rule!(require(global_caller(component_address)));
+                minter_updater => rule!(deny_all);
+            ))
+            .burn_roles(burn_roles!(
+                burner => rule!(require(global_caller(component_address)));
+                burner_updater => rule!(deny_all);
+            ))
+            .create_with_no_initial_supply();
+
+            // Create the AccountLocker to store undeliverable airdrops in; only this component
+            // can store and recover coins, users have to claim them through the component so that
+            // the 401k deposit and withdraw restrictions are respected
+            let account_locker = Blueprint::<AccountLocker>::instantiate(
+                OwnerRole::Updatable(rule!(require(owner_badge_address))),
+                rule!(require(global_caller(component_address))),
+                rule!(deny_all),
+                rule!(require(global_caller(component_address))),
+                rule!(deny_all),
+                None,
+            );
+
+            // Instantiate the component and globalize it
+            let component = Self {
+                max_supply: max_supply,
+                supply_cap: max_supply * dec!("0.99"),
+                min_buy_xrd_amount: Decimal::ZERO,
+                min_sell_amount: Decimal::ZERO,
+                dust_threshold: Decimal::ZERO,
+                close_dust_positions: false,
+                closed_dust_supply: Decimal::ZERO,
+                presale: presale,
+                trading_starts_at: trading_starts_at,
+                anti_sniper_fee: Decimal::ZERO,
+                anti_sniper_minutes: 0,
+                presale_allowlist: KeyValueStore::new_with_registered_type(),
+                presale_rebates: match presale_rebates_bucket {
+                    None => Vault::new(XRD),
+                    Some(bucket) => Vault::with_bucket(bucket),
+                },
+                auction: None,
+                auction_commitments: KeyValueStore::new_with_registered_type(),
+                graduation_xrd_amount: Decimal::ZERO,
+                graduation_xrd_percentage: Decimal::ZERO,
+                graduation_pool: None,
+                pool_units: None,
+                validator: None,
+                staking_percentage: Decimal::ZERO,
+                lsus: None,
+                claim_nfts: None,
+                pool_stake: StakedXrd {
+                    staked: Decimal::ZERO,
+                    unstaking: Decimal::ZERO,
+                },
+                jackpot_stake: StakedXrd {
+                    staked: Decimal::ZERO,
+                    unstaking: Decimal::ZERO,
+                },
+                payout_stake: StakedXrd {
+                    staked: Decimal::ZERO,
+                    unstaking: Decimal::ZERO,
+                },
+                dividends_stake: StakedXrd {
+                    staked: Decimal::ZERO,
+                    unstaking: Decimal::ZERO,
+                },
+                payouts: Vault::new(XRD),
+                pending_xrd_liability: Decimal::ZERO,
+                fake_initial_xrd: (initial_price * max_supply).into(),
+                curve_change_supply: curve_change_supply_percentage * max_supply,
+                price_amplifier: price_amplifier,
+                pool: Vault::new(XRD),
+                dividends: Vault::new(XRD),
+                dividends_percentage: dividends_percentage,
+                jackpot: Vault::new(XRD),
+                jackpot_percentage: jackpot_percentage,
+                deposit_badge_manager: deposit_badge_manager,
+                withdraw_badge_manager: withdraw_badge_manager,
+                coin_manager: coin_manager,
+                next_badge_id: 1,
+                users: KeyValueStore::new_with_registered_type(),
+                user_index: KeyValueStore::new_with_registered_type(),
+                holders_count: 0,
+                active_holders_count: 0,
+                component_holders: KeyValueStore::new_with_registered_type(),
+                history: KeyValueStore::new_with_registered_type(),
+                history_lengths: KeyValueStore::new_with_registered_type(),
                 transaction_hash: Runtime::transaction_hash(),
-                dividends_per_401k: Decimal::ZERO,
+                dividends_per_401k: PreciseDecimal::ZERO,
                 ath: Decimal::ZERO,
                 jackpot_threshold: jackpot_threshold,
                 jackpot_threshold_time: jackpot_threshold_time,
@@ -394,6 +1523,27 @@ mod club401k {
                 current_jackpot_number: 1,
                 current_jackpot_amount: Decimal::ZERO,
                 jackpots: KeyValueStore::new_with_registered_type(),
+                min_jackpot_supply: Decimal::ZERO,
+                dividends_liability: Decimal::ZERO,
+                jackpot_liability: Decimal::ZERO,
+                dust_tolerance: dec!("0.000001"),
+                dividends_dust: PreciseDecimal::ZERO,
+                jackpot_dust: PreciseDecimal::ZERO,
+                reward_tokens: KeyValueStore::new_with_registered_type(),
+                reward_token_addresses: vec![],
+                treasury: Vault::new(XRD),
+                order_receipt_manager: order_receipt_manager,
+                limit_orders: KeyValueStore::new_with_registered_type(),
+                next_order_id: 1,
+                dca_receipt_manager: dca_receipt_manager,
+                dca_subscriptions: KeyValueStore::new_with_registered_type(),
+                next_subscription_id: 1,
+                transfer_dividends_percentage: Decimal::ZERO,
+                transfer_jackpot_percentage: Decimal::ZERO,
+                account_locker: account_locker,
+                next_campaign_id: 1,
+                airdrop_campaigns: KeyValueStore::new_with_registered_type(),
+                airdrop_allocations: KeyValueStore::new_with_registered_type(),
             }
             .instantiate()
             .prepare_to_globalize(OwnerRole::Updatable(rule!(require(owner_badge_address))))
@@ -403,6 +1553,11 @@ mod club401k {
                 seller => rule!(allow_all);
                 staker => rule!(allow_all);
                 airdropper => rule!(allow_all);
+                claimer => rule!(allow_all);
+                donor => rule!(allow_all);
+                transferrer => rule!(deny_all);
+                treasurer => rule!(deny_all);
+                keeper => rule!(allow_all);
             ))
             .globalize();
 
@@ -428,15 +1583,218 @@ mod club401k {
             self.transaction_hash = transaction_hash;
         }
 
+        // Internal method that returns the pool XRD amount, staked and unstaking XRD included
+        fn pool_xrd_amount(&self) -> Decimal {
+            self.pool.amount() + self.pool_stake.staked + self.pool_stake.unstaking
+        }
+
+        // Internal method that returns the jackpot XRD amount, staked and unstaking XRD included
+        fn jackpot_xrd_amount(&self) -> Decimal {
+            self.jackpot.amount() + self.jackpot_stake.staked + self.jackpot_stake.unstaking
+        }
+
+        /* Internal method that moves the ownership of some staked or unstaking XRD from a vault
+         * to another one; unstaking XRD are moved first, so that they are available sooner
+         */
+        fn move_stake(
+            // The current owner of the XRD
+            from: &mut StakedXrd,
+            // The new owner of the XRD
+            to: &mut StakedXrd,
+            // The amount of XRD to move
+            amount: Decimal,
+        ) {
+            let unstaking_amount = amount.min(from.unstaking);
+            from.unstaking -= unstaking_amount;
+            to.unstaking += unstaking_amount;
+
+            let staked_amount = amount - unstaking_amount;
+            from.staked -= staked_amount;
+            to.staked += staked_amount;
+        }
+
+        // Internal method that tells whether a new unstake can start below MAX_CLAIM_NFTS
+        fn can_unstake(&self) -> bool {
+            match self.claim_nfts {
+                None => true,
+                Some(ref claim_nfts) => claim_nfts.amount() < Decimal::from(MAX_CLAIM_NFTS),
+            }
+        }
+
+        /* Internal method that starts unstaking the staked XRD owed to the users, so that the
+         * pending XRD of sells and jackpot withdrawals become liquid after the unbonding delay.
+         * While MAX_CLAIM_NFTS claim NFTs exist, the next sell or rebalance_stake starts it.
+         */
+        fn unstake_payouts(&mut self) {
+            if self.payout_stake.staked == Decimal::ZERO || !self.can_unstake() {
+                return;
+            }
+
+            self.unstake_xrd(self.payout_stake.staked);
+            self.payout_stake.unstaking += self.payout_stake.staked;
+            self.payout_stake.staked = Decimal::ZERO;
+        }
+
+        /* Internal method that aligns the staked XRD of the pool, jackpot, payouts and dividends
+         * with the redemption value of the LSUs.
+         * The staking yield is added to the staked dividends; a loss (validator slashing) is taken
+         * from the staked dividends first, then from the pool and the jackpot, so that the XRD
+         * owed to the users stay funded as long as possible.
+         * It returns the harvested yield and the loss.
+         */
+        fn update_staked_value(&mut self) -> (Decimal, Decimal) {
+            let staked_value = match (self.validator, &self.lsus) {
+                (Some(validator), Some(lsus)) => validator.get_redemption_value(lsus.amount()),
+                _ => return (Decimal::ZERO, Decimal::ZERO),
+            };
+            let staked_amount = self.pool_stake.staked + self.jackpot_stake.staked +
+                self.payout_stake.staked + self.dividends_stake.staked;
+
+            // The staking yield belongs to the dividends
+            if staked_value >= staked_amount {
+                let harvested_amount = staked_value - staked_amount;
+                self.dividends_stake.staked += harvested_amount;
+
+                return (harvested_amount, Decimal::ZERO);
+            }
+
+            // Take the loss from the staked dividends, pool, jackpot and payouts in this order;
+            // the jackpot loss reduces the next jackpot
+            let slashed_amount = staked_amount - staked_value;
+            let mut loss = slashed_amount;
+            let dividends_loss = loss.min(self.dividends_stake.staked);
+            self.dividends_stake.staked -= dividends_loss;
+            loss -= dividends_loss;
+            let pool_loss = loss.min(self.pool_stake.staked);
+            self.pool_stake.staked -= pool_loss;
+            loss -= pool_loss;
+            let jackpot_loss = loss.min(self.jackpot_stake.staked);
+            self.jackpot_stake.staked -= jackpot_loss;
+            self.current_jackpot_amount = (self.current_jackpot_amount - jackpot_loss).max(Decimal::ZERO);
+            loss -= jackpot_loss;
+            self.payout_stake.staked = (self.payout_stake.staked - loss).max(Decimal::ZERO);
+
+            (Decimal::ZERO, slashed_amount)
+        }
+
+        /* Internal method that claims from the validator the unstaked XRD of every claim NFT
+         * whose epoch has come.
+         * The claimed XRD go to the payouts vault first, then back to the pool and to the jackpot;
+         * the rest are yield and dividends and are distributed.
+         * It returns the amount of claimed XRD.
+         */
+        fn claim_unstaked_xrd(&mut self) -> Decimal {
+            let mut validator = match self.validator {
+                None => return Decimal::ZERO,
+                Some(validator) => validator,
+            };
+
+            // No more than MAX_CLAIM_NFTS claim NFTs can exist, so they are all checked
+            let current_epoch = Runtime::current_epoch();
+            let mut xrd_bucket = Bucket::new(XRD);
+            let mut all_claimed = true;
+            if let Some(ref mut claim_nfts) = self.claim_nfts {
+                for claim_nft in claim_nfts.non_fungibles::<UnstakeData>(MAX_CLAIM_NFTS).iter() {
+                    if claim_nft.data().claim_epoch <= current_epoch {
+                        let claim_nft_bucket = claim_nfts.take_non_fungible(claim_nft.local_id());
+                        xrd_bucket.put(
+                            validator.claim_xrd(claim_nft_bucket).into()
+                        );
+                    } else {
+                        all_claimed = false;
+                    }
+                }
+            }
+            let claimed_amount = xrd_bucket.amount();
+
+            // The XRD owed to the users come first
+            let payouts_amount = xrd_bucket.amount().min(self.payout_stake.unstaking);
+            self.payout_stake.unstaking -= payouts_amount;
+            self.payouts.put(
+                xrd_bucket.take(payouts_amount)
+            );
+
+            // Then the pool and the jackpot get back their XRD
+            let pool_amount = xrd_bucket.amount().min(self.pool_stake.unstaking);
+            self.pool_stake.unstaking -= pool_amount;
+            self.pool.put(
+                xrd_bucket.take(pool_amount)
+            );
+            let jackpot_amount = xrd_bucket.amount().min(self.jackpot_stake.unstaking);
+            self.jackpot_stake.unstaking -= jackpot_amount;
+            self.jackpot.put(
+                xrd_bucket.take(jackpot_amount)
+            );
+
+            // The rest goes to the dividends
+            let dividends_amount = xrd_bucket.amount();
+            self.dividends_stake.unstaking = (self.dividends_stake.unstaking - dividends_amount).max(Decimal::ZERO);
+            self.dividends.put(xrd_bucket);
+            self.distribute_dividends(dividends_amount);
+
+            // Once nothing is being unstaked anymore, forget the rounding residues
+            if all_claimed {
+                self.payout_stake.unstaking = Decimal::ZERO;
+                self.pool_stake.unstaking = Decimal::ZERO;
+                self.jackpot_stake.unstaking = Decimal::ZERO;
+                self.dividends_stake.unstaking = Decimal::ZERO;
+            }
+
+            claimed_amount
+        }
+
+        // Internal method that starts unstaking LSUs worth the specified XRD amount
+        fn unstake_xrd(
+            &mut self,
+            // XRD amount to unstake
+            amount: Decimal,
+        ) {
+            let mut validator = self.validator.unwrap();
+            let lsus = self.lsus.as_mut().unwrap();
+
+            // Compute the LSUs to unstake
+            let lsu_amount = match amount >= validator.get_redemption_value(lsus.amount()) {
+                true => lsus.amount(),
+                false => lsus.amount() * amount / validator.get_redemption_value(lsus.amount()),
+            };
+            if lsu_amount == Decimal::ZERO {
+                return;
+            }
+
+            // Unstake them and keep the claim NFT
+            let claim_nft_bucket = validator.unstake(lsus.take(lsu_amount));
+            match self.claim_nfts {
+                None => self.claim_nfts = Some(NonFungibleVault::with_bucket(claim_nft_bucket)),
+                Some(ref mut claim_nfts) => claim_nfts.put(claim_nft_bucket),
+            }
+        }
+
         /* Internal method that computes constant product formula with the addition of some fake
          * XRD to set an initial price and to pump when more than curve_change_supply 401k has
-         * been minted
+         * been minted.
+         * After graduation the constant product of the TwoResourcePool vaults is returned instead.
          */
         fn constant_product(&self) -> (
+            // The constant product
             PreciseDecimal,
+            // XRD in the pool, fake XRD included
             PreciseDecimal,
+            // 401k in the pool: the ones that can still be minted or the ones in the
+            // TwoResourcePool
             Decimal,
         ) {
+            if let Some(ref pool) = self.graduation_pool {
+                let vault_amounts = pool.get_vault_amounts();
+                let xrd_amount = PreciseDecimal::from(*vault_amounts.get(&XRD).unwrap());
+                let coins_in_pool = *vault_amounts.get(&self.coin_manager.address()).unwrap();
+
+                return (
+                    xrd_amount * coins_in_pool,
+                    xrd_amount,
+                    coins_in_pool,
+                );
+            }
+
             // Current 401k coin supply
             let current_supply = self.coin_manager.total_supply().unwrap();
 
@@ -452,180 +1810,331 @@ mod club401k {
             };
 
             // Total XRD amount to use in the constant product formula
-            let xrd_amount = self.pool.amount() + fake_xrd;
+            let xrd_amount = self.pool_xrd_amount() + fake_xrd;
 
             // The constant product
-            let constant_product = xrd_amount * (self.max_supply - current_supply);
+            let coins_in_pool = self.max_supply - current_supply;
+            let constant_product = xrd_amount * coins_in_pool;
 
             (
                 constant_product,
                 xrd_amount,
-                current_supply,
+                coins_in_pool,
             )
         }
 
-        /* This internal method check if enough time has passed below the threshold to distribute
-         * the current jackpot; if so it assigns the current jackpot and starts a new jackpot cycle.
+        // Internal method that tells whether no more 401k can be minted by buying them
+        fn supply_cap_reached(&self) -> bool {
+            self.graduation_pool.is_none() && self.coin_manager.total_supply().unwrap() >= self.supply_cap
+        }
+
+        /* Internal method that trades against the graduation TwoResourcePool: it deposits the
+         * input bucket and withdraws the requested amount of the other resource, using a deposit
+         * or withdraw badge for the 401k.
+         * Before the 401k of the TwoResourcePool change, its holder gets its dividends and jackpot
+         * shares compounded in the TwoResourcePool and its rewards distributed to the other
+         * holders.
          */
-        fn check_jackpot_trigger(
+        fn trade_on_graduation_pool(
             &mut self,
-            // Current 401k coin price
-            price: Decimal,
-        ) {
-            // Proceed only if the price is below the threshold, set the period start to a future
-            // date otherwise
-            if price > self.jackpot_threshold * self.ath {
-                self.below_jackpot_threshold_since = i64::MAX;
-                return;
+            // The XRD or 401k to deposit
+            input_bucket: Bucket,
+            // Amount of the other resource to withdraw
+            output_amount: Decimal,
+        ) -> Bucket {
+            let mut pool = self.graduation_pool.unwrap();
+            let pool_address = pool.address();
+            let coin_address = self.coin_manager.address();
+
+            // Take the dividends, jackpot shares and rewards of the TwoResourcePool holder
+            let mut component_holder = self.component_holders.get(&pool_address).unwrap().clone();
+            let (mut xrd_bucket, jackpot_bucket, reward_buckets, _, _) =
+                self.settle_withdraw(&mut component_holder.holder);
+            if let Some(jackpot_bucket) = jackpot_bucket {
+                xrd_bucket.put(jackpot_bucket);
+            }
+            for reward_bucket in reward_buckets {
+                self.distribute_rewards(reward_bucket);
             }
 
-            // Get current time
-            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            // Deposit the input and withdraw the output
+            let output_bucket = match input_bucket.resource_address() == coin_address {
+                true => {
+                    let deposit_badge_bucket = self.mint_deposit_badge();
+                    deposit_badge_bucket.authorize_with_all(
+                        || {
+                            pool.protected_deposit(input_bucket.as_fungible())
+                        }
+                    );
+                    self.burn_badge(deposit_badge_bucket);
 
-            // Check that enough time has passed below the threshold
-            if now > self.below_jackpot_threshold_since + self.jackpot_threshold_time {
+                    pool.protected_withdraw(XRD, output_amount, WithdrawStrategy::Exact)
+                },
+                false => {
+                    pool.protected_deposit(input_bucket.as_fungible());
 
-                // Compute the amount of jackpot to associate to each 401k coin
-                let prize_per_401k = self.current_jackpot_amount / self.coin_manager.total_supply().unwrap();
+                    let withdraw_badge_bucket = self.mint_withdraw_badge();
+                    let coin_bucket = withdraw_badge_bucket.authorize_with_all(
+                        || {
+                            pool.protected_withdraw(coin_address, output_amount, WithdrawStrategy::Exact)
+                        }
+                    );
+                    self.burn_badge(withdraw_badge_bucket);
 
-                // Emit the JackpotDistributedEvent event
-                Runtime::emit_event(
-                    JackpotDistributedEvent {
-                        jackpot_amount: self.current_jackpot_amount,
-                        prize_per_401k: prize_per_401k,
-                    }
-                );
+                    coin_bucket
+                },
+            };
 
-                // Insert information about the closed jackpot cycle in the KVS
-                self.jackpots.insert(
-                    self.current_jackpot_number,
-                    Jackpot {
-                        prize_per_401k: prize_per_401k,
-                    }
-                );
+            // Compound the dividends and jackpot shares in the TwoResourcePool
+            match xrd_bucket.amount() > Decimal::ZERO {
+                true => pool.protected_deposit(xrd_bucket.as_fungible()),
+                false => xrd_bucket.drop_empty(),
+            }
 
-                // Start a new jackpot cycle
-                self.current_jackpot_number += 1;
-                self.current_jackpot_amount = Decimal::ZERO;
-                self.below_jackpot_threshold_since = i64::MAX;
-                self.ath = price;
+            // The holder follows the 401k in the TwoResourcePool
+            component_holder.holder.current_bought_amount = *pool.get_vault_amounts().get(&coin_address).unwrap();
+            self.component_holders.insert(
+                pool_address,
+                component_holder,
+            );
 
-            // If the period below the the threshold starts now, take note of the current time
-            } else if self.below_jackpot_threshold_since == i64::MAX {
-                self.below_jackpot_threshold_since = now;
-            }
+            output_bucket.into()
         }
 
-        /* This internal method verifies if one or more jackpots have been distributed since the
-         * last operation from buyer; it also returns a bucket with the jackpot share of the buyer
-         * if requested.
+        /* Internal method that checks that trading has started or the presale is in progress and
+         * that the opening batch auction, if any, has been closed
          */
-        fn check_won_jackpots(
-            &mut self,
-            // The buyer whose pending jackpots are to be found
-            user: &mut User,
-            // Whether return the jackpot share for the user or not
-            withdraw: bool,
-        ) -> Option<Bucket> {
+        fn check_trading_started(&self) {
+            assert!(
+                match self.auction {
+                    None => true,
+                    Some(ref auction) => auction.closed,
+                },
+                "Auction not closed yet"
+            );
+            assert!(
+                Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch >= self.trading_starts_at ||
+                    self.presale_in_progress(),
+                "Trading not started yet"
+            );
+        }
 
-            // Initialize a variable to store the jackpot amount for the user
-            let mut accrued_jackpot = Decimal::ZERO;
+        /* Internal method that returns the percentage of XRD to pay to the jackpot when buying
+         * and selling 401k, anti-sniper fee included
+         */
+        fn trade_jackpot_percentage(&self) -> Decimal {
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            let anti_sniper_seconds = self.anti_sniper_minutes * 60;
+            let elapsed = now - self.trading_starts_at;
 
-            // For each jackpot distributed after the last operation of this user
-            for jackpot_number in user.current_jackpot_number..self.current_jackpot_number {
+            // The anti-sniper fee decays linearly from the trading start
+            match elapsed >= 0 && elapsed < anti_sniper_seconds {
+                false => self.jackpot_percentage,
+                true => self.jackpot_percentage + self.anti_sniper_fee *
+                    Decimal::from(anti_sniper_seconds - elapsed) / Decimal::from(anti_sniper_seconds),
+            }
+        }
 
-                // Add his share to the accrued_jackpot variable
-                accrued_jackpot += user.current_bought_amount *
-                    self.jackpots.get(&jackpot_number).unwrap().prize_per_401k;
+        // Internal method that tells whether the presale is in progress
+        fn presale_in_progress(&self) -> bool {
+            match self.presale {
+                None => false,
+                Some(ref presale) =>
+                    Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch < presale.ends_at,
             }
+        }
 
-            // Has been some pending jackpot share found?
-            match accrued_jackpot > Decimal::ZERO {
-                // If not, ruturn None
-                false => None,
-                // If yes, check if the withdraw of the jackpot share has been requested
-                true => match withdraw {
-                    // If not, accrue the jackpot to the user without returning it, also take note
-                    // that the jackpot(s) has been computed for this user
-                    false => {
-                        user.accrued_jackpot += accrued_jackpot;
-                        user.current_jackpot_number = self.current_jackpot_number;
-                        None
-                    },
-                    // If yes, return a bucket with all of the new and old accrued jackpots and
-                    // take note that there are no more pending snapshots for this user
-                    true => {
-                        accrued_jackpot += user.accrued_jackpot;
-                        user.accrued_jackpot = Decimal::ZERO;
-                        user.current_jackpot_number = self.current_jackpot_number;
-                        Some(self.jackpot.take(accrued_jackpot))
-                    },
+        /* Internal method that computes the XRD to give back to a presale buyer, so that he pays
+         * the presale price instead of the bonding curve one; zero if the presale is not in
+         * progress
+         */
+        fn presale_rebate(
+            &self,
+            // XRD spent by the buyer, fees included
+            xrd_amount: Decimal,
+            // Price the 401k were bought at on the bonding curve
+            curve_price: Decimal,
+        ) -> Decimal {
+            if !self.presale_in_progress() {
+                return Decimal::ZERO;
+            }
+
+            match self.presale.as_ref().unwrap().price {
+                // Give back the XRD paid above the fixed price, if any
+                PresalePrice::Fixed(price) => match price < curve_price {
+                    true => xrd_amount * (Decimal::ONE - price / curve_price),
+                    false => Decimal::ZERO,
                 },
-            }       
+                // Give back the discount on the bonding curve price
+                PresalePrice::Discount(discount) => xrd_amount * discount,
+            }
         }
 
-        fn accrew_dividends(
+        /* Internal method that computes the XRD needed to buy on the bonding curve the 401k that
+         * a fixed price presale buyer is paying for, when the bonding curve is cheaper than the
+         * presale price; None if this is not the case
+         */
+        fn presale_fixed_price_xrd(
             &self,
-            // The user whose dividends must me accrued
-            user: &mut User,
-        )  {
-            // Accrew the accumulated dividends since the token bought
-            user.accrued_dividends += user.current_bought_amount *
-                (self.dividends_per_401k - user.dividends_per_401k);
+            // XRD to buy 401k with, fees excluded
+            xrd_amount: Decimal,
+        ) -> Option<Decimal> {
+            if !self.presale_in_progress() {
+                return None;
+            }
+            let price = match self.presale.as_ref().unwrap().price {
+                PresalePrice::Fixed(price) => price,
+                PresalePrice::Discount(_) => return None,
+            };
 
-            // No more dividends to accrew
-            user.dividends_per_401k = self.dividends_per_401k;
+            // 401k paid at the presale price
+            let (constant_product, xrd_in_pool, coins_in_pool) = self.constant_product();
+            let coin_amount = xrd_amount / price;
+            if coin_amount >= coins_in_pool {
+                return None;
+            }
+
+            // XRD needed to buy them on the bonding curve
+            let curve_xrd_amount = Decimal::try_from(
+                (constant_product / (coins_in_pool - coin_amount) - xrd_in_pool)
+                    .checked_round(18, RoundingMode::AwayFromZero)
+                    .unwrap()
+            ).expect("XRD amount overflow");
+
+            match curve_xrd_amount < xrd_amount {
+                true => Some(curve_xrd_amount),
+                false => None,
+            }
         }
 
-        /* This method exchanges a bucket of XRD for a bucket of 401k coins.
-         * A deposit badge is provided so that the user can deposit the 401k coins in his account.
-         * The deposit badge must be returned to the post_buy method; it contains information
-         * that the post_buy method needs.
+        /* Internal method that burns a bucket of 401k coins and takes the corresponding XRD out
+         * of the pool according to the bonding curve; no fee is applied here.
+         * If the pool doesn't have enough liquid XRD, the missing part is taken from the staked
+         * or unstaking pool XRD and moved to the payouts, and its unstaking starts; the caller
+         * must take note of whom it's owed to.
+         * After graduation the 401k are sold to the TwoResourcePool instead.
          */
-        pub fn buy(
+        fn swap_401k_for_xrd(
             &mut self,
-            // XRDs to buy 401k coins
-            mut xrd_bucket: Bucket,
+            // The 401k coins to burn
+            coin_bucket: FungibleBucket,
         ) -> (
-            // 401k coins
-            FungibleBucket,
-            // deposit badge
-            NonFungibleBucket
+            // Liquid XRD
+            Bucket,
+            // XRD moved to the payouts because they are not liquid
+            Decimal,
         ) {
-            // Check that no other invocation to buy or pre_sell methods happended in this
-            // transaction
-            self.check_transaction();
+            // Get informations needed to compute the XRD proceeds
+            let (constant_product, xrd_in_pool, coins_in_pool) = self.constant_product();
 
-            // Check that the XRD bucket is not empty
-            let xrd_amount = xrd_bucket.amount();
-            assert!(
-                xrd_amount > Decimal::ZERO,
-                "No XRD provided"
+            // Compute the XRD amount
+            let new_xrd_in_pool = Decimal::try_from(constant_product / (coins_in_pool + coin_bucket.amount()))
+                .expect("XRD in pool overflow");
+            let xrd_amount = Decimal::try_from(xrd_in_pool).expect("XRD in pool overflow") -
+                new_xrd_in_pool;
+
+            // After graduation the XRD are always liquid in the TwoResourcePool
+            if self.graduation_pool.is_some() {
+                return (
+                    self.trade_on_graduation_pool(coin_bucket.into(), xrd_amount),
+                    Decimal::ZERO,
+                );
+            }
+
+            // Burn the 401k
+            coin_bucket.burn();
+
+            // Claim the unstaked XRD that are ready if the liquid ones are not enough
+            if self.pool.amount() < xrd_amount {
+                self.claim_unstaked_xrd();
+            }
+
+            // Take the liquid XRD, the missing part will be paid when its unstaking completes
+            let liquid_amount = xrd_amount.min(self.pool.amount());
+            let deferred_amount = xrd_amount - liquid_amount;
+            Self::move_stake(
+                &mut self.pool_stake,
+                &mut self.payout_stake,
+                deferred_amount,
             );
+            self.unstake_payouts();
 
-            // Take the XRD share to use as dividends
-            let dividends_amount = xrd_amount * self.dividends_percentage;
+            (
+                self.pool.take(liquid_amount),
+                deferred_amount,
+            )
+        }
+
+        /* Internal method that takes the dividends and jackpot fees of a sale from its liquid XRD.
+         * The part of the fees that doesn't fit in the liquid XRD is taken from the payouts of
+         * the sale: the missing jackpot is added to the staked jackpot, the missing dividends are
+         * distributed when their unstaking completes.
+         */
+        fn pay_sale_fees(
+            &mut self,
+            // Liquid XRD of the sale
+            xrd_bucket: &mut Bucket,
+            // Amount of dividends to pay
+            dividends_amount: Decimal,
+            // Amount of jackpot to pay
+            jackpot_amount: Decimal,
+        ) {
+            // Deposit the liquid dividends and compute the updated dividends per 401k coin
+            let liquid_dividends_amount = dividends_amount.min(xrd_bucket.amount());
             self.dividends.put(
-                xrd_bucket.take(dividends_amount)
+                xrd_bucket.take(liquid_dividends_amount)
+            );
+            self.distribute_dividends(liquid_dividends_amount);
+            Self::move_stake(
+                &mut self.payout_stake,
+                &mut self.dividends_stake,
+                dividends_amount - liquid_dividends_amount,
             );
 
-            // Take the XRD share to add to the jackpot
-            let jackpot_amount = xrd_amount * self.jackpot_percentage;
+            // Add the fee to the jackpot
+            let liquid_jackpot_amount = jackpot_amount.min(xrd_bucket.amount());
             self.jackpot.put(
-                xrd_bucket.take(jackpot_amount)
+                xrd_bucket.take(liquid_jackpot_amount)
+            );
+            Self::move_stake(
+                &mut self.payout_stake,
+                &mut self.jackpot_stake,
+                jackpot_amount - liquid_jackpot_amount,
             );
             self.current_jackpot_amount += jackpot_amount;
+        }
 
+        /* Internal method that deposits a bucket of XRD in the pool and mints the corresponding
+         * 401k coins according to the bonding curve; no fee is applied here.
+         * After graduation the 401k are bought from the TwoResourcePool instead.
+         * It also updates the ATH information and checks if a jackpot distribution has been
+         * triggered.
+         */
+        fn swap_xrd_for_401k(
+            &mut self,
+            // The XRD to deposit in the pool
+            xrd_bucket: Bucket,
+        ) -> (
+            // The minted 401k coins
+            FungibleBucket,
+            // Price the 401k were bought at
+            Decimal,
+        ) {
             // Get informations needed to compute bought amount
-            let (constant_product, mut xrd_in_pool, current_supply) = self.constant_product();
+            let (constant_product, xrd_in_pool, coins_in_pool) = self.constant_product();
 
-            // Deposit the remainig XRDs in the pool
+            // Compute the bought 401k amount and make sure it can be minted
             let deposited_xrd = xrd_bucket.amount();
-            self.pool.put(xrd_bucket);
-            xrd_in_pool += deposited_xrd;
-
-            // Compute the bought 401k amount
-            let bought_amount = self.max_supply - Decimal::try_from(constant_product / xrd_in_pool).unwrap() - current_supply;
+            let bought_amount = coins_in_pool -
+                Decimal::try_from(constant_product / (xrd_in_pool + deposited_xrd)).expect("Coins in pool overflow");
+            assert!(
+                bought_amount > Decimal::ZERO,
+                "Too few XRD to buy any 401k"
+            );
+            assert!(
+                bought_amount < coins_in_pool,
+                "Max supply exceeded"
+            );
 
             // Compute the bought price and update ATH information if needed
             let price = deposited_xrd / bought_amount;
@@ -641,87 +2150,267 @@ mod club401k {
                 self.check_jackpot_trigger(price);
             }
 
-            // Mint the bought 401k coins
-            let coin_bucket = self.coin_manager.mint(bought_amount);
-
-            // Mint the deposit badge
-            let deposit_badge_bucket = self.deposit_badge_manager.mint_non_fungible(
-                &NonFungibleLocalId::integer(self.next_badge_id.into()),
-                DepositBadge {
-                    bought_amount: bought_amount,
-                    price: price,
-                    dividends_amount: dividends_amount,
-                }
-            );
+            // Deposit the XRD in the pool and mint the bought 401k coins, or buy them from the
+            // TwoResourcePool after graduation
+            let coin_bucket = match self.graduation_pool {
+                None => {
+                    self.pool.put(xrd_bucket);
+                    self.coin_manager.mint(bought_amount)
+                },
+                Some(_) => self.trade_on_graduation_pool(xrd_bucket, bought_amount).as_fungible(),
+            };
 
             (
                 coin_bucket,
-                deposit_badge_bucket,
+                price,
             )
         }
 
-        /* This method registers in which account the bought 401k has been deposited and burns the
-         * used deposit badge
+        /* Internal method that computes the XRD amount (fees included) needed to buy the
+         * specified amount of 401k coins; the result is rounded as requested by the caller
          */
-        pub fn post_buy(
+        fn xrd_needed_for(
+            &self,
+            // The amount of 401k to buy
+            amount: Decimal,
+            // AwayFromZero to be sure that the XRD are enough to buy the 401k, ToZero to be sure
+            // that they don't buy more than the 401k
+            rounding_mode: RoundingMode,
+        ) -> Decimal {
+            // Get informations needed to compute the XRD amount
+            let (constant_product, xrd_in_pool, coins_in_pool) = self.constant_product();
+
+            // Make sure that so many 401k can be minted, or bought from the TwoResourcePool
+            assert!(
+                match self.graduation_pool {
+                    None => self.coin_manager.total_supply().unwrap() + amount <= self.supply_cap,
+                    Some(_) => amount < coins_in_pool,
+                },
+                "Not enough 401k available below the supply cap"
+            );
+
+            // XRD to deposit in the pool, then add the fees
+            let deposited_xrd = constant_product / (coins_in_pool - amount) - xrd_in_pool;
+            let xrd_amount = deposited_xrd /
+                (Decimal::ONE - self.dividends_percentage - self.trade_jackpot_percentage());
+
+            Decimal::try_from(
+                xrd_amount.checked_round(18, rounding_mode).unwrap()
+            ).unwrap()
+        }
+
+        /* Internal method that computes the XRD amount (fees included) needed to reach the supply
+         * cap; the result is rounded down so that it never buys more than the supply cap
+         */
+        fn xrd_for_supply_cap(&self) -> Decimal {
+            // Make sure that some 401k can still be bought
+            assert!(
+                !self.supply_cap_reached(),
+                "Supply cap reached, no 401k available"
+            );
+            let current_supply = self.coin_manager.total_supply().unwrap();
+
+            self.xrd_needed_for(
+                self.supply_cap - current_supply,
+                RoundingMode::ToZero,
+            )
+        }
+
+        /* Internal method that takes out of a bucket the XRD exceeding the amount (fees
+         * included) needed to reach the supply cap, so that they can be refunded; after
+         * graduation there is no supply cap
+         */
+        fn take_excess_xrd(
+            &self,
+            // The XRD to buy 401k with
+            xrd_bucket: &mut Bucket,
+        ) -> Bucket {
+            if self.graduation_pool.is_some() {
+                return Bucket::new(XRD);
+            }
+
+            // Is the bucket bigger than needed to reach the supply cap?
+            let max_xrd_amount = self.xrd_for_supply_cap();
+            let xrd_amount = xrd_bucket.amount();
+            if xrd_amount <= max_xrd_amount {
+                return Bucket::new(XRD);
+            }
+
+            // Take the excess XRD and emit the SupplyCapReachedEvent event
+            let refund_bucket = xrd_bucket.take(xrd_amount - max_xrd_amount);
+            Runtime::emit_event(
+                SupplyCapReachedEvent {
+                    supply_cap: self.supply_cap,
+                    refunded_xrd_amount: refund_bucket.amount(),
+                }
+            );
+
+            refund_bucket
+        }
+
+        /* Internal method shared by all of the buys: it puts aside the XRD exceeding the supply
+         * cap, takes the dividends and jackpot fees (unless they must be skipped), buys the 401k
+         * on the bonding curve and adds the presale rebate, if any, to the refund.
+         * During a fixed price presale, when the bonding curve is cheaper than the presale price,
+         * the XRD exceeding the bonding curve cost go to the presale rebates vault.
+         * The dividends are deposited but not distributed, the caller must distribute them.
+         */
+        fn buy_with_fees(
             &mut self,
-            // Account where the 401k has been saved
-            account: Global<Account>,
-            // The deposit badge
-            deposit_badge_bucket: NonFungibleBucket,
+            // The XRD to buy 401k with
+            mut xrd_bucket: Bucket,
+            // Whether the dividends and jackpot fees must be skipped
+            skip_fees: bool,
+        ) -> (
+            // The bought 401k coins
+            FungibleBucket,
+            // Information about the buy
+            DepositBadge,
+            // XRD refund
+            Bucket,
         ) {
-            // Check that the account owner has actually been involved in this transaction
-            Runtime::assert_access_rule(account.get_owner_role().rule);
+            // Put aside the XRD exceeding the supply cap, they will be refunded
+            let mut refund_bucket = self.take_excess_xrd(&mut xrd_bucket);
+            let xrd_amount = xrd_bucket.amount();
 
-            // Check that the deposit_badge_bucket really contains a deposit badge
+            // Take the XRD shares to use as dividends and to add to the jackpot
+            let (dividends_amount, jackpot_amount) = match skip_fees {
+                true => (Decimal::ZERO, Decimal::ZERO),
+                false => (
+                    xrd_amount * self.dividends_percentage,
+                    xrd_amount * self.trade_jackpot_percentage(),
+                ),
+            };
+            self.dividends.put(
+                xrd_bucket.take(dividends_amount)
+            );
+            self.jackpot.put(
+                xrd_bucket.take(jackpot_amount)
+            );
+            self.current_jackpot_amount += jackpot_amount;
+
+            // Make the buyer pay the fixed presale price even if the bonding curve is cheaper; the
+            // difference funds the rebates of the next presale buys
+            if let Some(curve_xrd_amount) = self.presale_fixed_price_xrd(xrd_bucket.amount()) {
+                self.presale_rebates.put(
+                    xrd_bucket.take(xrd_bucket.amount() - curve_xrd_amount)
+                );
+            }
+
+            // Deposit the remainig XRDs in the pool and mint the bought 401k coins
+            let (coin_bucket, price) = self.swap_xrd_for_401k(xrd_bucket);
+
+            // During the presale the 401k are bought on the bonding curve too; the difference
+            // with the presale price is refunded from the presale rebates vault
+            let rebate_amount = self.presale_rebate(xrd_amount, price);
             assert!(
-                deposit_badge_bucket.resource_address() == self.deposit_badge_manager.address(),
-                "Wrong badge"
+                rebate_amount <= self.presale_rebates.amount(),
+                "Not enough XRD in the presale rebates vault"
+            );
+            refund_bucket.put(
+                self.presale_rebates.take(rebate_amount)
             );
 
-            // Make sue that exacly one deposit badge is in the deposit_badge_bucket and get its
-            // NonFungibleData
-            let deposit_badge = deposit_badge_bucket.non_fungible::<DepositBadge>().data();
+            let deposit_badge = DepositBadge {
+                bought_amount: coin_bucket.amount(),
+                price: price,
+                dividends_amount: dividends_amount,
+                xrd_amount: xrd_amount - rebate_amount,
+                jackpot_amount: jackpot_amount,
+            };
+
+            (
+                coin_bucket,
+                deposit_badge,
+                refund_bucket,
+            )
+        }
+
+        /* Internal method that computes the XRD amount (fees included) that can be spent without
+         * buying the 401k at a price higher than max_price; the result is rounded down
+         */
+        fn xrd_for_max_price(
+            &self,
+            // The highest acceptable price
+            max_price: Decimal,
+        ) -> Decimal {
+            // Get informations needed to compute the XRD amount
+            let (_, xrd_in_pool, coins_in_pool) = self.constant_product();
+
+            // The price of a buy is (xrd_in_pool + deposited_xrd) / coins_in_pool
+            let deposited_xrd = PreciseDecimal::from(max_price) * coins_in_pool - xrd_in_pool;
+            if deposited_xrd <= PreciseDecimal::ZERO {
+                return Decimal::ZERO;
+            }
+
+            // Add the fees
+            let xrd_amount = deposited_xrd /
+                (Decimal::ONE - self.dividends_percentage - self.trade_jackpot_percentage());
+
+            Decimal::try_from(
+                xrd_amount.checked_round(18, RoundingMode::ToZero).unwrap()
+            ).unwrap()
+        }
+
+        /* Internal method that returns the current spot price of the 401k on the bonding curve,
+         * or on the TwoResourcePool after graduation
+         */
+        fn spot_price(&self) -> Decimal {
+            let (_, xrd_in_pool, coins_in_pool) = self.constant_product();
+
+            Decimal::try_from(xrd_in_pool / coins_in_pool)
+                .expect("Price overflow")
+        }
+
+        // Internal method that returns the integer id of a receipt
+        fn receipt_id(
+            // The local id of the receipt
+            local_id: &NonFungibleLocalId,
+        ) -> u64 {
+            match local_id {
+                NonFungibleLocalId::Integer(id) => id.value(),
+                _ => Runtime::panic("Wrong receipt id".to_string()),
+            }
+        }
+
+        /* Internal method that buys 401k with the provided XRD on behalf of an account and sends
+         * them to it; if the deposit fails, they are stored in the AccountLocker.
+         * It returns the bought amount, the price and the XRD exceeding the supply cap.
+         */
+        fn buy_for_account(
+            &mut self,
+            // The account to send the 401k to
+            account: Global<Account>,
+            // The XRD to buy 401k with
+            xrd_bucket: Bucket,
+        ) -> (
+            // Amount of bought 401k
+            Decimal,
+            // Price the 401k were bought at
+            Decimal,
+            // XRD refund
+            Bucket,
+        ) {
+            // Take the fees and buy the 401k coins; the XRD exceeding the supply cap are refunded
+            let (coin_bucket, deposit_badge, refund_bucket) = self.buy_with_fees(xrd_bucket, false);
+            let price = deposit_badge.price;
 
             // Is the buyer already registered?
             let mut buyer = match self.users.get(&account) {
                 // If not create a new one
-                None => User {
-                    current_bought_amount: Decimal::ZERO,
-                    dividends_per_401k: self.dividends_per_401k,
-                    accrued_dividends: Decimal::ZERO,
-                    current_jackpot_number: self.current_jackpot_number,
-                    accrued_jackpot: Decimal::ZERO,
-                },
+                None => self.new_user(),
                 Some(buyer) => buyer.clone(),
             };
 
-            // Accrew past dividends and jackpots to the buyer
-            self.accrew_dividends(&mut buyer);
-            _ = self.check_won_jackpots(&mut buyer, false);
-            
-            // Compute the new global dividends amount per 401k coin
-            self.dividends_per_401k +=
-                deposit_badge.dividends_amount / self.coin_manager.total_supply().unwrap();
-
-            // Update the bought amount for this buyer
-            buyer.current_bought_amount += deposit_badge.bought_amount;
-
-            // Accrew his own share of the dividends he paid to the buyer
-            self.accrew_dividends(&mut buyer);
-
-            // Check that the bought 401k have really been deposited in the specified account
-            let coin_address = self.coin_manager.address();
-            assert!(
-                account.balance(coin_address) == buyer.current_bought_amount,
-                "Where are the 401ks gone?"
-            );
+            // Update the buyer with the bought 401k and the dividends he paid, then send them
+            self.settle_buy(&mut buyer, &deposit_badge);
+            buyer.locked_amount += self.deposit_or_lock(account, coin_bucket);
 
             // Emit the BuyEvent event
             Runtime::emit_event(
                 BuyEvent {
                     account: account,
-                    price: deposit_badge.price,
+                    price: price,
                     bought_amount: deposit_badge.bought_amount,
                     current_jackpot_amount: self.current_jackpot_amount,
                     global_dividends_per_401k: self.dividends_per_401k,
@@ -731,358 +2420,3621 @@ mod club401k {
                 }
             );
 
-            // Update saved buyer information
-            self.users.insert(
+            // Update saved buyer information and history
+            self.save_user(
                 account,
                 buyer,
             );
+            self.record_history(
+                account,
+                HistoryOperation::Buy,
+                deposit_badge.bought_amount,
+                deposit_badge.xrd_amount,
+                price,
+            );
 
-            // Burn the deposit badge and be ready to mint the next one
-            deposit_badge_bucket.burn();
-            self.next_badge_id += 1;
-        }
-
-        /* This method mints a withdraw badge that can be used to take some 401k out of and
-         * account to sell them.
-         * The withdraw badge must be returned to the sell method.
-         */
-        pub fn pre_sell(&mut self) -> NonFungibleBucket {
-            // Check that no other invocation to buy or pre_sell methods happended in this
-            // transaction
-            self.check_transaction();
-
-            // Mint a withdraw badge
-            self.withdraw_badge_manager.mint_non_fungible(
-                &NonFungibleLocalId::integer(self.next_badge_id.into()),
-                WithdrawBadge {
-                }
+            (
+                deposit_badge.bought_amount,
+                price,
+                refund_bucket,
             )
         }
 
-        /* This method accepts a bucket of 401k coin and swaps them for XRD.
-         * It also burns the provided withdraw badge.
+        /* Internal method that buys 401k with the provided XRD and burns them, so the XRD stay
+         * in the pool and the price grows.
+         * A buyback is handled exactly like a buy: the jackpot fee is added to the current jackpot
+         * before the swap, and the swap updates the ATH or checks the jackpot trigger, so a
+         * buyback can set a new ATH or distribute a jackpot that was already due (fee included).
+         * It returns the XRD exceeding the supply cap.
          */
-        pub fn sell(
+        fn settle_buyback(
             &mut self,
-            // Bucket of 401k coins
-            coin_bucket: FungibleBucket,
-            // The account address of the seller
-            account: Global<Account>,
-            // The used withdraw badge
-            withdraw_badge_bucket: NonFungibleBucket,
+            // The XRD to buy back 401k with
+            xrd_bucket: Bucket,
+            // Whether the dividends and jackpot fees must be skipped
+            skip_fees: bool,
+            // Whether the XRD came from the treasury vault
+            from_treasury: bool,
         ) -> Bucket {
-            // Check that the account owner has actually been involved in this transaction
-            Runtime::assert_access_rule(account.get_owner_role().rule);
-
-            // Check that coin_bucket contains a non zero amount of 401k coins
-            let coin_address = self.coin_manager.address();
-            assert!(
-                coin_bucket.resource_address() == coin_address,
-                "Wrong coin"
-            );
-            let coin_amount = coin_bucket.amount();
-            assert!(
-                coin_amount > Decimal::ZERO,
-                "No 401k provided"
-            );
-
-            // Check that withdraw_badge_bucket contains exacty one withdraw badge
+            // Check that trading has started and it's not a presale
+            self.check_trading_started();
             assert!(
-                withdraw_badge_bucket.resource_address() == self.withdraw_badge_manager.address(),
-                "Wrong badge"
+                !self.presale_in_progress(),
+                "Presale in progress"
             );
             assert!(
-                withdraw_badge_bucket.amount() == Decimal::ONE,
-                "Exactly one withdraw badge required"
+                xrd_bucket.resource_address() == XRD && xrd_bucket.amount() > Decimal::ZERO,
+                "No XRD provided"
             );
-
-            // Get informations needed to compute the XRD proceeds
-            let (constant_product, xrd_in_pool, _) = self.constant_product();
-
-            // Get existing information about the seller
-            let mut seller = self.users.get(&account).unwrap().clone();
-            
-            // Accrew him any past dividends and jackpot
-            self.accrew_dividends(&mut seller);
-            _ = self.check_won_jackpots(&mut seller, false);
-           
-            // Check that the 401k coins really came from the specified account
-            let withdrawn_401k = seller.current_bought_amount - account.balance(coin_address);
             assert!(
-                coin_amount == withdrawn_401k,
-                "Where these 401k came from?"
+                self.earning_supply() > Decimal::ZERO,
+                "Nobody holds 401k"
             );
 
-            // Update the account owned coins amount
-            seller.current_bought_amount -= withdrawn_401k;
-           
-            // Burn the sold 401k and get the new supply
+            // Take the fees unless they must be skipped, buy the 401k and burn them; the XRD
+            // exceeding the supply cap are refunded
+            let (coin_bucket, deposit_badge, refund_bucket) = self.buy_with_fees(xrd_bucket, skip_fees);
             coin_bucket.burn();
-            let current_supply = self.coin_manager.total_supply().unwrap();
-            
-            // Burn the withdraw badge and be ready to mint the next one
-            withdraw_badge_bucket.burn();
-            self.next_badge_id += 1;
-
-            // Compute the XRD amount from the sale
-            let coins_in_pool = self.max_supply - current_supply;
-            let new_xrd_in_pool = Decimal::try_from(constant_product / coins_in_pool).unwrap();
-            let xrd_amount = Decimal::try_from(xrd_in_pool).unwrap() - new_xrd_in_pool;
-            let mut xrd_bucket = self.pool.take(xrd_amount);
 
-            // Take the due dividends out of the XRD and deposit them
-            let dividends_amount = xrd_amount * self.dividends_percentage;
-            self.dividends.put(
-                xrd_bucket.take(dividends_amount)
-            );
+            // The dividends go to the holders of the remaining supply
+            self.distribute_dividends(deposit_badge.dividends_amount);
 
-            // Take the jackpot percentage out of the XRDs
-            let jackpot_amount = xrd_amount * self.jackpot_percentage;
-            self.jackpot.put(
-                xrd_bucket.take(jackpot_amount)
+            // Emit the BuybackEvent event
+            Runtime::emit_event(
+                BuybackEvent {
+                    xrd_amount: deposit_badge.xrd_amount,
+                    from_treasury: from_treasury,
+                    burned_amount: deposit_badge.bought_amount,
+                    price: deposit_badge.price,
+                    dividends_amount: deposit_badge.dividends_amount,
+                    jackpot_amount: deposit_badge.jackpot_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                }
             );
-            self.current_jackpot_amount += jackpot_amount;
 
-            // Compute the updated dividends per 401k coin
-            if current_supply > Decimal::ZERO {
-                self.dividends_per_401k += dividends_amount / current_supply;
-            }
+            refund_bucket
+        }
 
-            // Compute the sale price and check if a jackpot has been triggered
-            let price = xrd_amount / coin_amount;
-            if price < self.ath * self.jackpot_threshold {
-                self.check_jackpot_trigger(price);
-            }
+        /* Internal method that mints a deposit badge for the component itself, so that it can
+         * put 401k in a vault; it must be burned by the burn_badge method
+         */
+        fn mint_deposit_badge(&self) -> NonFungibleBucket {
+            self.deposit_badge_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(self.next_badge_id.into()),
+                DepositBadge {
+                    bought_amount: Decimal::ZERO,
+                    price: Decimal::ZERO,
+                    dividends_amount: Decimal::ZERO,
+                    xrd_amount: Decimal::ZERO,
+                    jackpot_amount: Decimal::ZERO,
+                }
+            )
+        }
 
-            // Accrew his own share of the dividends he paid to the seller if he still owns some
+        /* Internal method that mints a withdraw badge for the component itself, so that it can
+         * take 401k out of a vault; it must be burned by the burn_badge method
+         */
+        fn mint_withdraw_badge(&self) -> NonFungibleBucket {
+            self.withdraw_badge_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(self.next_badge_id.into()),
+                WithdrawBadge {
+                }
+            )
+        }
+
+        // Internal method that burns a deposit or withdraw badge, ready to mint the next one
+        fn burn_badge(
+            &mut self,
+            // The badge to burn
+            badge_bucket: NonFungibleBucket,
+        ) {
+            badge_bucket.burn();
+            self.next_badge_id += 1;
+        }
+
+        /* Internal method that uses a deposit badge to send 401k coins to an account; if the
+         * account refuses them, they are stored in the AccountLocker.
+         * It returns the amount of 401k stored in the AccountLocker.
+         */
+        fn deposit_or_lock(
+            &mut self,
+            // The account to send the 401k to
+            account: Global<Account>,
+            // The 401k coins
+            coin_bucket: FungibleBucket,
+        ) -> Decimal {
+            let amount = coin_bucket.amount();
+            if amount == Decimal::ZERO {
+                coin_bucket.burn();
+                return Decimal::ZERO;
+            }
+
+            let deposit_badge_bucket = self.mint_deposit_badge();
+            let refund = deposit_badge_bucket.authorize_with_all(
+                || {
+                    account.clone().try_deposit_or_refund(
+                        coin_bucket.into(),
+                        None,
+                    )
+                }
+            );
+            let locked_amount = match refund {
+                None => Decimal::ZERO,
+                Some(refund) => {
+                    deposit_badge_bucket.authorize_with_all(
+                        || {
+                            self.account_locker.store(
+                                account,
+                                refund,
+                                false,
+                            );
+                        }
+                    );
+                    amount
+                },
+            };
+            self.burn_badge(deposit_badge_bucket);
+
+            locked_amount
+        }
+
+        /* This internal method check if enough time has passed below the threshold to distribute
+         * the current jackpot; if so it assigns the current jackpot and starts a new jackpot cycle.
+         */
+        fn check_jackpot_trigger(
+            &mut self,
+            // Current 401k coin price
+            price: Decimal,
+        ) {
+            // Proceed only if the price is below the threshold, set the period start to a future
+            // date otherwise
+            if price > self.jackpot_threshold * self.ath {
+                self.below_jackpot_threshold_since = i64::MAX;
+                return;
+            }
+
+            // Get current time
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Check that enough time has passed below the threshold
+            if now > self.below_jackpot_threshold_since + self.jackpot_threshold_time {
+
+                // If there are too few 401k to distribute the jackpot to, keep it for the next
+                // cycle
+                let current_supply = self.earning_supply();
+                if current_supply == Decimal::ZERO || current_supply < self.min_jackpot_supply {
+
+                    // Emit the JackpotRolledOverEvent event
+                    Runtime::emit_event(
+                        JackpotRolledOverEvent {
+                            jackpot_amount: self.current_jackpot_amount,
+                            current_supply: current_supply,
+                        }
+                    );
+
+                    // Start a new cycle with the same jackpot
+                    self.below_jackpot_threshold_since = i64::MAX;
+                    self.ath = price;
+                    return;
+                }
+
+                // Compute the amount of jackpot to associate to each 401k coin
+                let prize_per_401k = PreciseDecimal::from(self.current_jackpot_amount) / current_supply;
+                self.jackpot_liability += self.current_jackpot_amount;
+
+                // Emit the JackpotDistributedEvent event
+                Runtime::emit_event(
+                    JackpotDistributedEvent {
+                        jackpot_amount: self.current_jackpot_amount,
+                        prize_per_401k: prize_per_401k,
+                    }
+                );
+
+                // Insert information about the closed jackpot cycle in the KVS
+                self.jackpots.insert(
+                    self.current_jackpot_number,
+                    Jackpot {
+                        prize_per_401k: prize_per_401k,
+                    }
+                );
+
+                // Start a new jackpot cycle
+                self.current_jackpot_number += 1;
+                self.current_jackpot_amount = Decimal::ZERO;
+                self.below_jackpot_threshold_since = i64::MAX;
+                self.ath = price;
+
+            // If the period below the the threshold starts now, take note of the current time
+            } else if self.below_jackpot_threshold_since == i64::MAX {
+                self.below_jackpot_threshold_since = now;
+            }
+        }
+
+        /* This internal method verifies if one or more jackpots have been distributed since the
+         * last operation from buyer; it also returns a bucket with the jackpot share of the buyer
+         * if requested.
+         */
+        fn check_won_jackpots(
+            &mut self,
+            // The buyer whose pending jackpots are to be found
+            user: &mut User,
+            // Whether return the jackpot share for the user or not
+            withdraw: bool,
+        ) -> Option<Bucket> {
+
+            // Initialize a variable to store the jackpot amount for the user
+            let mut accrued_jackpot = PreciseDecimal::ZERO;
+
+            // For each jackpot distributed after the last operation of this user
+            for jackpot_number in user.current_jackpot_number..self.current_jackpot_number {
+
+                // Add his share to the accrued_jackpot variable
+                accrued_jackpot += user.current_bought_amount *
+                    self.jackpots.get(&jackpot_number).unwrap().prize_per_401k;
+            }
+
+            // Accrue the new jackpot shares to the user, the rounding residue becomes dust, and
+            // take note that the jackpot(s) have been computed for this user
+            let accrued_jackpot_amount = Decimal::try_from(accrued_jackpot).unwrap();
+            self.jackpot_dust += accrued_jackpot - accrued_jackpot_amount;
+            user.accrued_jackpot += accrued_jackpot_amount;
+            user.current_jackpot_number = self.current_jackpot_number;
+
+            // If requested, return a bucket with all of the new and old accrued jackpots
+            match withdraw && user.accrued_jackpot > Decimal::ZERO {
+                false => None,
+                true => {
+                    let jackpot_amount = user.accrued_jackpot;
+                    user.accrued_jackpot = Decimal::ZERO;
+                    let (jackpot_bucket, pending_amount) = self.take_jackpot(jackpot_amount);
+                    user.pending_xrd_amount += pending_amount;
+                    self.pending_xrd_liability += pending_amount;
+                    Some(jackpot_bucket)
+                },
+            }
+        }
+
+        /* Internal method that checks a donation before it's deposited: there must be somebody to
+         * donate to and the sponsor, if any, must have been involved in this transaction
+         */
+        fn check_donation(
+            &self,
+            // Amount of donated XRD or reward tokens
+            amount: Decimal,
+            // The account of the sponsor
+            sponsor: &Option<Global<Account>>,
+            // A message from the sponsor
+            message: &Option<String>,
+        ) {
+            assert!(
+                amount > Decimal::ZERO,
+                "Empty donation"
+            );
+            assert!(
+                message.as_ref().map_or(true, |message| message.len() <= MAX_DONATION_MESSAGE_LENGTH),
+                "Message too long"
+            );
+            assert!(
+                self.earning_supply() > Decimal::ZERO,
+                "Nobody to donate to"
+            );
+            if let Some(sponsor) = sponsor {
+                Runtime::assert_access_rule(sponsor.get_owner_role().rule);
+            }
+        }
+
+        // Internal method that emits the DonationEvent event for a deposited donation
+        fn finalize_donation(
+            &self,
+            // Where the donation goes
+            target: DonationTarget,
+            // Amount of donated XRD or reward tokens
+            amount: Decimal,
+            // The account of the sponsor
+            sponsor: Option<Global<Account>>,
+            // A message from the sponsor
+            message: Option<String>,
+        ) {
+            // Emit the DonationEvent event
+            Runtime::emit_event(
+                DonationEvent {
+                    target: target,
+                    amount: amount,
+                    sponsor: sponsor,
+                    message: message,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                    current_jackpot_amount: self.current_jackpot_amount,
+                }
+            );
+        }
+
+        /* Internal method that returns the 401k supply that earns dividends, jackpots and
+         * rewards: the 401k in closed dust positions are excluded
+         */
+        fn earning_supply(&self) -> Decimal {
+            self.coin_manager.total_supply().unwrap() - self.closed_dust_supply
+        }
+
+        /* Internal method that gives back to a user the 401k of his closed dust position; it must
+         * be called before checking his account balance
+         */
+        fn reopen_dust_position(
+            &mut self,
+            // The user whose dust position must be reopened
+            user: &mut User,
+        ) {
+            if user.closed_dust_amount == Decimal::ZERO {
+                return;
+            }
+
+            // Make sure that the dust earns nothing for the time it was closed
+            self.accrew_dividends(user);
+            _ = self.check_won_jackpots(user, false);
+
+            user.current_bought_amount += user.closed_dust_amount;
+            self.closed_dust_supply -= user.closed_dust_amount;
+            user.closed_dust_amount = Decimal::ZERO;
+        }
+
+        // Internal method that adds XRD to the dividends of the current 401k holders
+        fn distribute_dividends(
+            &mut self,
+            // Amount of XRD already deposited in the dividends vault
+            dividends_amount: Decimal,
+        ) {
+            // If nobody owns 401k there's nobody to distribute the dividends to
+            let current_supply = self.earning_supply();
+            if current_supply == Decimal::ZERO {
+                return;
+            }
+
+            // Compute the new global dividends amount per 401k coin
+            self.dividends_per_401k += PreciseDecimal::from(dividends_amount) / current_supply;
+            self.dividends_liability += dividends_amount;
+        }
+
+        /* Internal method that withdraws dividends from the dividends vault.
+         * If the vault doesn't contain enough XRD because of rounding, the withdrawal is reduced
+         * to the available amount as long as the shortfall is within the dust tolerance.
+         */
+        fn take_dividends(
+            &mut self,
+            // Amount of dividends to withdraw
+            amount: Decimal,
+        ) -> Bucket {
+            let available_amount = self.dividends.amount();
+            assert!(
+                amount <= available_amount + self.dust_tolerance,
+                "Not enough dividends in the vault"
+            );
+
+            self.dividends_liability = (self.dividends_liability - amount).max(Decimal::ZERO);
+            self.dividends.take(amount.min(available_amount))
+        }
+
+        /* Internal method that withdraws distributed jackpots from the jackpot vault; the amount
+         * of the next jackpot is never touched.
+         * If the vault doesn't contain enough XRD because of rounding, the withdrawal is reduced
+         * to the available amount as long as the shortfall is within the dust tolerance.
+         * The part of the withdrawal that isn't liquid is moved from the staked jackpot to the
+         * payouts and its unstaking starts; the caller must take note of whom it's owed to.
+         */
+        fn take_jackpot(
+            &mut self,
+            // Amount of jackpot to withdraw
+            amount: Decimal,
+        ) -> (
+            // Liquid XRD
+            Bucket,
+            // XRD moved to the payouts because they are not liquid
+            Decimal,
+        ) {
+            let available_amount = (self.jackpot_xrd_amount() - self.current_jackpot_amount).max(Decimal::ZERO);
+            assert!(
+                amount <= available_amount + self.dust_tolerance,
+                "Not enough jackpot in the vault"
+            );
+
+            self.jackpot_liability = (self.jackpot_liability - amount).max(Decimal::ZERO);
+            let amount = amount.min(available_amount);
+            let liquid_amount = amount.min(self.jackpot.amount());
+            let deferred_amount = amount - liquid_amount;
+            Self::move_stake(
+                &mut self.jackpot_stake,
+                &mut self.payout_stake,
+                deferred_amount,
+            );
+            self.unstake_payouts();
+
+            (
+                self.jackpot.take(liquid_amount),
+                deferred_amount,
+            )
+        }
+
+        fn accrew_dividends(
+            &mut self,
+            // The user whose dividends must me accrued
+            user: &mut User,
+        )  {
+            // Accrew the accumulated dividends since the token bought, the rounding residue
+            // becomes dust
+            let accrued_dividends = user.current_bought_amount *
+                (self.dividends_per_401k - user.dividends_per_401k);
+            let accrued_dividends_amount = Decimal::try_from(accrued_dividends).unwrap();
+            self.dividends_dust += accrued_dividends - accrued_dividends_amount;
+            user.accrued_dividends += accrued_dividends_amount;
+
+            // No more dividends to accrew
+            user.dividends_per_401k = self.dividends_per_401k;
+
+            // Do the same for each reward token; the rounding residue becomes dust of the token
+            for resource_address in self.reward_token_addresses.iter() {
+                let mut reward_token = self.reward_tokens.get_mut(resource_address).unwrap();
+                let user_reward = user.rewards.entry(*resource_address).or_insert(
+                    UserReward {
+                        reward_per_401k: PreciseDecimal::ZERO,
+                        accrued_amount: Decimal::ZERO,
+                    }
+                );
+                let accrued_reward = user.current_bought_amount *
+                    (reward_token.reward_per_401k - user_reward.reward_per_401k);
+                let accrued_reward_amount = Decimal::try_from(accrued_reward).unwrap();
+                reward_token.dust += accrued_reward - accrued_reward_amount;
+                user_reward.accrued_amount += accrued_reward_amount;
+                user_reward.reward_per_401k = reward_token.reward_per_401k;
+            }
+        }
+
+        /* Internal method that moves a fraction of the rewards accrued to a holder to another
+         * one; both must be up to date with rewards.
+         */
+        fn move_accrued_rewards(
+            // The holder to take the rewards from
+            from: &mut User,
+            // The holder to give the rewards to
+            to: &mut User,
+            // Fraction of the rewards to move (0-1 range)
+            fraction: Decimal,
+        ) {
+            for (resource_address, from_reward) in from.rewards.iter_mut() {
+                let amount = from_reward.accrued_amount * fraction;
+                from_reward.accrued_amount -= amount;
+                to.rewards.entry(*resource_address).or_insert(
+                    UserReward {
+                        reward_per_401k: from_reward.reward_per_401k,
+                        accrued_amount: Decimal::ZERO,
+                    }
+                ).accrued_amount += amount;
+            }
+        }
+
+        // Internal method that returns the amount of withdrawn rewards for each reward token
+        fn reward_amounts(
+            // The withdrawn rewards
+            reward_buckets: &[Bucket],
+        ) -> IndexMap<ResourceAddress, Decimal> {
+            reward_buckets.iter()
+                .map(|bucket| (bucket.resource_address(), bucket.amount()))
+                .collect()
+        }
+
+        // Internal method that adds reward tokens to the rewards of the current 401k holders
+        fn distribute_rewards(
+            &mut self,
+            // The reward tokens
+            reward_bucket: Bucket,
+        ) {
+            let current_supply = self.earning_supply();
+            assert!(
+                current_supply > Decimal::ZERO,
+                "Nobody to distribute to"
+            );
+
+            let mut reward_token = self.reward_tokens.get_mut(&reward_bucket.resource_address())
+                .expect("Not a reward token");
+            reward_token.reward_per_401k += PreciseDecimal::from(reward_bucket.amount()) / current_supply;
+            reward_token.liability += reward_bucket.amount();
+            reward_token.vault.put(reward_bucket);
+        }
+
+        // Internal method that takes the rewards accrued to a holder; he must be up to date
+        fn take_rewards(
+            &mut self,
+            // The holder to take the rewards for
+            user: &mut User,
+        ) -> Vec<Bucket> {
+            let mut reward_buckets: Vec<Bucket> = vec![];
+            for (resource_address, user_reward) in user.rewards.iter_mut() {
+                if user_reward.accrued_amount == Decimal::ZERO {
+                    continue;
+                }
+
+                // Only whole units of the token can be withdrawn, the rest becomes dust
+                let mut reward_token = self.reward_tokens.get_mut(resource_address).unwrap();
+                let amount = user_reward.accrued_amount
+                    .checked_round(reward_token.divisibility, RoundingMode::ToZero)
+                    .unwrap();
+                reward_token.dust += user_reward.accrued_amount - amount;
+                reward_token.liability = (reward_token.liability - user_reward.accrued_amount).max(Decimal::ZERO);
+                user_reward.accrued_amount = Decimal::ZERO;
+
+                // If the vault is slightly short because of rounding, reduce the withdrawal to
+                // the available amount; the tolerance is at least one unit of the token
+                let available_amount = reward_token.vault.amount();
+                let unit = Decimal::ONE / Decimal::from(10u64.pow(reward_token.divisibility.into()));
+                assert!(
+                    amount <= available_amount + self.dust_tolerance.max(unit),
+                    "Not enough rewards in the vault"
+                );
+                if amount > Decimal::ZERO {
+                    reward_buckets.push(
+                        reward_token.vault.take(amount.min(available_amount))
+                    );
+                }
+            }
+
+            reward_buckets
+        }
+
+        // Internal method that returns an empty User with nothing to accrue from the past
+        fn new_user(&self) -> User {
+            User {
+                current_bought_amount: Decimal::ZERO,
+                dividends_per_401k: self.dividends_per_401k,
+                accrued_dividends: Decimal::ZERO,
+                current_jackpot_number: self.current_jackpot_number,
+                accrued_jackpot: Decimal::ZERO,
+                locked_amount: Decimal::ZERO,
+                vestings: vec![],
+                stats: UserStats {
+                    total_bought_amount: Decimal::ZERO,
+                    total_sold_amount: Decimal::ZERO,
+                    total_xrd_spent: Decimal::ZERO,
+                    total_xrd_received: Decimal::ZERO,
+                    total_fees_paid: Decimal::ZERO,
+                    total_dividends_withdrawn: Decimal::ZERO,
+                    total_jackpot_withdrawn: Decimal::ZERO,
+                    first_buy_at: 0,
+                    last_activity_at: 0,
+                    average_cost: Decimal::ZERO,
+                    realized_pnl: Decimal::ZERO,
+                },
+                rewards: self.reward_token_addresses.iter()
+                    .map(|resource_address| (
+                        *resource_address,
+                        UserReward {
+                            reward_per_401k: self.reward_tokens.get(resource_address).unwrap().reward_per_401k,
+                            accrued_amount: Decimal::ZERO,
+                        }
+                    ))
+                    .collect(),
+                closed_dust_amount: Decimal::ZERO,
+                pending_xrd_amount: Decimal::ZERO,
+            }
+        }
+
+        /* Internal method that saves a user in the users KVS and keeps the holders index and
+         * counters up to date
+         */
+        fn save_user(
+            &mut self,
+            // The account of the user
+            account: Global<Account>,
+            // The updated user
+            user: User,
+        ) {
+            // Was the user already registered and did he own some 401k?
+            let previous_amount = self.users.get(&account).map(|user| user.current_bought_amount);
+            let was_active = match previous_amount {
+                // If the user is new, add his account to the index
+                None => {
+                    self.user_index.insert(
+                        self.holders_count,
+                        account,
+                    );
+                    self.holders_count += 1;
+                    false
+                },
+                Some(previous_amount) => previous_amount > Decimal::ZERO,
+            };
+
+            // Update the active holders counter
+            let is_active = user.current_bought_amount > Decimal::ZERO;
+            if is_active && !was_active {
+                self.active_holders_count += 1;
+            } else if was_active && !is_active {
+                self.active_holders_count -= 1;
+            }
+
+            self.users.insert(
+                account,
+                user,
+            );
+        }
+
+        // Internal method that appends an operation to the history of an account
+        fn record_history(
+            &mut self,
+            // The account that did the operation
+            account: Global<Account>,
+            // Kind of operation
+            operation: HistoryOperation,
+            // Amount of 401k involved
+            amount: Decimal,
+            // Amount of XRD involved
+            xrd_amount: Decimal,
+            // Price of the 401k
+            price: Decimal,
+        ) {
+            let sequence = match self.history_lengths.get(&account) {
+                None => 0,
+                Some(length) => *length,
+            };
+
+            self.history.insert(
+                (account, sequence),
+                HistoryEntry {
+                    operation: operation,
+                    amount: amount,
+                    xrd_amount: xrd_amount,
+                    price: price,
+                    timestamp: Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch,
+                }
+            );
+            self.history_lengths.insert(
+                account,
+                sequence + 1,
+            );
+        }
+
+        // Internal function that makes sure that a vesting schedule makes sense
+        fn check_vesting_schedule(
+            // The optional vesting schedule to check
+            vesting: &Option<VestingSchedule>,
+        ) {
+            if let Some(ref vesting) = vesting {
+                assert!(
+                    vesting.duration > 0,
+                    "Wrong vesting duration"
+                );
+                assert!(
+                    vesting.cliff >= 0 && vesting.cliff <= vesting.duration,
+                    "Wrong vesting cliff"
+                );
+            }
+        }
+
+        /* Internal function that computes the amount of 401k of a user that are not vested yet;
+         * fully vested schedules are removed from the user
+         */
+        fn unvested_amount(
+            // The user whose vesting schedules must be checked
+            user: &mut User,
+        ) -> Decimal {
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Forget about the schedules that are over
+            user.vestings.retain(|vesting| now < vesting.start + vesting.duration);
+
+            // Sum the 401k not vested yet in each schedule
+            let mut unvested_amount = Decimal::ZERO;
+            for vesting in user.vestings.iter() {
+                let elapsed = now - vesting.start;
+                unvested_amount += match elapsed < vesting.cliff {
+                    true => vesting.amount,
+                    false => vesting.amount * Decimal::from(vesting.duration - elapsed) /
+                        Decimal::from(vesting.duration),
+                };
+            }
+
+            unvested_amount
+        }
+
+        /* Internal function that updates the lifetime statistics of a user getting some 401k.
+         * It must be called before adding the 401k to current_bought_amount.
+         */
+        fn record_purchase(
+            // The user getting the 401k
+            user: &mut User,
+            // Amount of 401k bought or received
+            amount: Decimal,
+            // XRD paid for the 401k, fees included (zero for airdrops and transfers)
+            xrd_amount: Decimal,
+            // XRD paid as dividends and jackpot fees
+            fees_amount: Decimal,
+        ) {
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Compute the new weighted average cost of the owned 401k
+            let new_amount = user.current_bought_amount + amount;
+            if new_amount > Decimal::ZERO {
+                user.stats.average_cost = (user.stats.average_cost * user.current_bought_amount +
+                    xrd_amount) / new_amount;
+            }
+
+            // Update the totals
+            user.stats.total_bought_amount += amount;
+            user.stats.total_xrd_spent += xrd_amount;
+            user.stats.total_fees_paid += fees_amount;
+            if user.stats.first_buy_at == 0 {
+                user.stats.first_buy_at = now;
+            }
+            user.stats.last_activity_at = now;
+        }
+
+        /* Internal function that updates the lifetime statistics of a user selling some 401k and
+         * returns the profit or loss realized by the sale.
+         * The average cost of the remaining 401k doesn't change.
+         */
+        fn record_sale(
+            // The seller
+            user: &mut User,
+            // Amount of 401k sold
+            amount: Decimal,
+            // XRD received, fees excluded
+            xrd_amount: Decimal,
+            // XRD paid as dividends and jackpot fees
+            fees_amount: Decimal,
+        ) -> Decimal {
+            let realized_pnl = xrd_amount - user.stats.average_cost * amount;
+
+            user.stats.total_sold_amount += amount;
+            user.stats.total_xrd_received += xrd_amount;
+            user.stats.total_fees_paid += fees_amount;
+            user.stats.realized_pnl += realized_pnl;
+            user.stats.last_activity_at = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Nothing owned, no cost basis
+            if user.current_bought_amount == Decimal::ZERO {
+                user.stats.average_cost = Decimal::ZERO;
+            }
+
+            realized_pnl
+        }
+
+        /* Internal function that merges the lifetime statistics of a user into the ones of
+         * another user.
+         * It must be called before moving the 401k between the users.
+         */
+        fn merge_stats(
+            // The user receiving the statistics
+            user: &mut User,
+            // The user whose statistics are merged
+            other_user: &User,
+        ) {
+            // Compute the weighted average cost of the 401k of both users
+            let new_amount = user.current_bought_amount + other_user.current_bought_amount;
+            if new_amount > Decimal::ZERO {
+                user.stats.average_cost = (user.stats.average_cost * user.current_bought_amount +
+                    other_user.stats.average_cost * other_user.current_bought_amount) / new_amount;
+            }
+
+            // Sum the totals
+            user.stats.total_bought_amount += other_user.stats.total_bought_amount;
+            user.stats.total_sold_amount += other_user.stats.total_sold_amount;
+            user.stats.total_xrd_spent += other_user.stats.total_xrd_spent;
+            user.stats.total_xrd_received += other_user.stats.total_xrd_received;
+            user.stats.total_fees_paid += other_user.stats.total_fees_paid;
+            user.stats.total_dividends_withdrawn += other_user.stats.total_dividends_withdrawn;
+            user.stats.total_jackpot_withdrawn += other_user.stats.total_jackpot_withdrawn;
+            user.stats.realized_pnl += other_user.stats.realized_pnl;
+
+            // Keep the earliest first buy
+            if user.stats.first_buy_at == 0 ||
+                (other_user.stats.first_buy_at != 0 &&
+                other_user.stats.first_buy_at < user.stats.first_buy_at) {
+                user.stats.first_buy_at = other_user.stats.first_buy_at;
+            }
+            user.stats.last_activity_at = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+        }
+
+        /* Internal method that updates a holder with the 401k bought in the current operation
+         * and computes the new global dividends amount per 401k coin
+         */
+        fn settle_buy(
+            &mut self,
+            // The buyer
+            buyer: &mut User,
+            // NonFungibleData of the deposit badge issued by the buy method
+            deposit_badge: &DepositBadge,
+        ) {
+            // Accrew past dividends and jackpots to the buyer
+            self.accrew_dividends(buyer);
+            _ = self.check_won_jackpots(buyer, false);
+
+            // Compute the new global dividends amount per 401k coin
+            self.distribute_dividends(deposit_badge.dividends_amount);
+
+            // Update the statistics and the bought amount for this buyer
+            Self::record_purchase(
+                buyer,
+                deposit_badge.bought_amount,
+                deposit_badge.xrd_amount,
+                deposit_badge.dividends_amount + deposit_badge.jackpot_amount,
+            );
+            buyer.current_bought_amount += deposit_badge.bought_amount;
+
+            // Accrew his own share of the dividends he paid to the buyer
+            self.accrew_dividends(buyer);
+        }
+
+        /* Internal method that sells the 401k coins of a holder, takes the fees out of the XRD
+         * and checks if a jackpot has been triggered.
+         * The XRD that can't be paid with liquid XRD are added to the pending XRD of the seller.
+         * Past dividends and jackpots must already have been accrued to the seller.
+         */
+        fn settle_sell(
+            &mut self,
+            // The seller
+            seller: &mut User,
+            // The sold 401k coins
+            coin_bucket: FungibleBucket,
+        ) -> (
+            // XRD from the sale, fees excluded
+            Bucket,
+            // Price the 401k were sold at
+            Decimal,
+            // Profit or loss realized by the sale
+            Decimal,
+            // XRD from the sale added to the pending XRD of the seller
+            Decimal,
+        ) {
+            // Update the owned coins amount
+            let coin_amount = coin_bucket.amount();
+            seller.current_bought_amount -= coin_amount;
+
+            // Burn the sold 401k and get the XRD from the sale
+            let (mut xrd_bucket, deferred_amount) = self.swap_401k_for_xrd(coin_bucket);
+            let xrd_amount = xrd_bucket.amount() + deferred_amount;
+
+            // Take the dividends and jackpot fees out of the XRD
+            let dividends_amount = xrd_amount * self.dividends_percentage;
+            let jackpot_amount = xrd_amount * self.trade_jackpot_percentage();
+            self.pay_sale_fees(
+                &mut xrd_bucket,
+                dividends_amount,
+                jackpot_amount,
+            );
+
+            // The XRD owed to the seller that are not liquid will be paid later
+            let pending_amount = xrd_amount - dividends_amount - jackpot_amount - xrd_bucket.amount();
+            seller.pending_xrd_amount += pending_amount;
+            self.pending_xrd_liability += pending_amount;
+
+            // Compute the sale price and check if a jackpot has been triggered
+            let price = xrd_amount / coin_amount;
+            if price < self.ath * self.jackpot_threshold {
+                self.check_jackpot_trigger(price);
+            }
+
+            // Accrew his own share of the dividends he paid to the seller if he still owns some
             // 401k
             if seller.current_bought_amount > Decimal::ZERO {
-                self.accrew_dividends(&mut seller);
+                self.accrew_dividends(seller);
+            }
+
+            // Update the seller statistics
+            let realized_pnl = Self::record_sale(
+                seller,
+                coin_amount,
+                xrd_bucket.amount() + pending_amount,
+                dividends_amount + jackpot_amount,
+            );
+
+            (
+                xrd_bucket,
+                price,
+                realized_pnl,
+                pending_amount,
+            )
+        }
+
+        // Internal method that takes the pending dividends, jackpot shares and rewards of a holder
+        fn settle_withdraw(
+            &mut self,
+            // The holder to receive dividends, jackpot shares and rewards for
+            user: &mut User,
+        ) -> (
+            // Dividends, paid pending XRD included
+            Bucket,
+            // Jackpot shares
+            Option<Bucket>,
+            // Rewards
+            Vec<Bucket>,
+            // Withdrawn jackpot shares, the part added to the pending XRD included
+            Decimal,
+            // Paid pending XRD
+            Decimal,
+        ) {
+            // Accrew pending dividends to the user
+            self.accrew_dividends(user);
+
+            // Take the pending dividends
+            let mut dividends_bucket = self.take_dividends(user.accrued_dividends);
+
+            // Get any new or previously accrued jackpot shares; the part that isn't liquid is
+            // added to the pending XRD of the user
+            let pending_xrd_amount = user.pending_xrd_amount;
+            let jackpot_bucket = self.check_won_jackpots(user, true);
+            let withdrawn_jackpot = user.pending_xrd_amount - pending_xrd_amount + match jackpot_bucket {
+                None => Decimal::ZERO,
+                Some(ref bucket) => bucket.amount(),
+            };
+
+            // No more pending dividends for this user
+            user.accrued_dividends = Decimal::ZERO;
+
+            // Take the pending rewards
+            let reward_buckets = self.take_rewards(user);
+
+            // Update the user statistics
+            user.stats.total_dividends_withdrawn += dividends_bucket.amount();
+            user.stats.total_jackpot_withdrawn += withdrawn_jackpot;
+            user.stats.last_activity_at = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Add the pending XRD from past sells and jackpot withdrawals whose unstaking
+            // completed, as far as the payouts vault allows
+            if user.pending_xrd_amount > self.payouts.amount() {
+                self.claim_unstaked_xrd();
+            }
+            let mut settled_pending_xrd = user.pending_xrd_amount.min(self.payouts.amount());
+
+            // Once no payout is staked or unstaking anymore, a shortfall due to the unstaking
+            // rounding is tolerated within the dust tolerance
+            if self.payout_stake.staked + self.payout_stake.unstaking == Decimal::ZERO &&
+                user.pending_xrd_amount - settled_pending_xrd <= self.dust_tolerance {
+                settled_pending_xrd = user.pending_xrd_amount;
+            }
+            let paid_pending_xrd = settled_pending_xrd.min(self.payouts.amount());
+            user.pending_xrd_amount -= settled_pending_xrd;
+            self.pending_xrd_liability = (self.pending_xrd_liability - settled_pending_xrd).max(Decimal::ZERO);
+            dividends_bucket.put(
+                self.payouts.take(paid_pending_xrd)
+            );
+
+            (
+                dividends_bucket,
+                jackpot_bucket,
+                reward_buckets,
+                withdrawn_jackpot,
+                paid_pending_xrd,
+            )
+        }
+
+        /* This method exchanges a bucket of XRD for a bucket of 401k coins.
+         * A deposit badge is provided so that the user can deposit the 401k coins in his account.
+         * The deposit badge must be returned to the post_buy method; it contains information
+         * that the post_buy method needs.
+         * If the buy would exceed the supply cap, it is partially filled and the unused XRD are
+         * refunded.
+         */
+        pub fn buy(
+            &mut self,
+            // XRDs to buy 401k coins
+            xrd_bucket: Bucket,
+        ) -> (
+            // 401k coins
+            FungibleBucket,
+            // deposit badge
+            NonFungibleBucket,
+            // XRD refund
+            Bucket,
+        ) {
+            // Check that no other invocation to buy or pre_sell methods happended in this
+            // transaction
+            self.check_transaction();
+
+            // Check that trading has started
+            self.check_trading_started();
+
+            // Check that the XRD bucket is not empty and not too small
+            assert!(
+                xrd_bucket.amount() > Decimal::ZERO,
+                "No XRD provided"
+            );
+            assert!(
+                xrd_bucket.amount() >= self.min_buy_xrd_amount,
+                "Less XRD than the minimum buy amount"
+            );
+
+            // Take the fees and buy the 401k coins; the XRD exceeding the supply cap and the
+            // presale rebate, if any, are refunded
+            let (coin_bucket, deposit_badge, refund_bucket) = self.buy_with_fees(xrd_bucket, false);
+
+            // Mint the deposit badge
+            let deposit_badge_bucket = self.deposit_badge_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(self.next_badge_id.into()),
+                deposit_badge,
+            );
+
+            (
+                coin_bucket,
+                deposit_badge_bucket,
+                refund_bucket,
+            )
+        }
+
+        /* This method registers in which account the bought 401k has been deposited and burns the
+         * used deposit badge
+         */
+        pub fn post_buy(
+            &mut self,
+            // Account where the 401k has been saved
+            account: Global<Account>,
+            // The deposit badge
+            deposit_badge_bucket: NonFungibleBucket,
+        ) {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Check that the deposit_badge_bucket really contains a deposit badge
+            assert!(
+                deposit_badge_bucket.resource_address() == self.deposit_badge_manager.address(),
+                "Wrong badge"
+            );
+
+            // Make sue that exacly one deposit badge is in the deposit_badge_bucket and get its
+            // NonFungibleData
+            let deposit_badge = deposit_badge_bucket.non_fungible::<DepositBadge>().data();
+
+            // During the presale, check that the buyer is allowlisted and is not exceeding his cap
+            if self.presale_in_progress() {
+                let mut allowance = self.presale_allowlist.get_mut(&account)
+                    .expect("Account not in the presale allowlist");
+                assert!(
+                    deposit_badge.xrd_amount <= *allowance,
+                    "Presale cap exceeded"
+                );
+                *allowance -= deposit_badge.xrd_amount;
+            }
+
+            // Is the buyer already registered?
+            let mut buyer = match self.users.get(&account) {
+                // If not create a new one
+                None => self.new_user(),
+                Some(buyer) => buyer.clone(),
+            };
+
+            // Give the buyer back his closed dust position, if any
+            self.reopen_dust_position(&mut buyer);
+
+            // Update the buyer with the bought 401k and the dividends he paid
+            self.settle_buy(&mut buyer, &deposit_badge);
+
+            // Check that the bought 401k have really been deposited in the specified account (the
+            // ones in the AccountLocker are not in the account yet)
+            let coin_address = self.coin_manager.address();
+            assert!(
+                account.balance(coin_address) == buyer.current_bought_amount - buyer.locked_amount,
+                "Where are the 401ks gone?"
+            );
+
+            // Emit the BuyEvent event
+            Runtime::emit_event(
+                BuyEvent {
+                    account: account,
+                    price: deposit_badge.price,
+                    bought_amount: deposit_badge.bought_amount,
+                    current_jackpot_amount: self.current_jackpot_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                    ath: self.ath,
+                    buyer_total_accrued_dividends: buyer.accrued_dividends,
+                    buyer_accrued_jackpot: buyer.accrued_jackpot,
+                }
+            );
+
+            // Update saved buyer information and history
+            self.save_user(
+                account,
+                buyer,
+            );
+            self.record_history(
+                account,
+                HistoryOperation::Buy,
+                deposit_badge.bought_amount,
+                deposit_badge.xrd_amount,
+                deposit_badge.price,
+            );
+
+            // Burn the deposit badge and be ready to mint the next one
+            deposit_badge_bucket.burn();
+            self.next_badge_id += 1;
+        }
+
+        /* This method mints a withdraw badge that can be used to take some 401k out of and
+         * account to sell them.
+         * The withdraw badge must be returned to the sell method.
+         */
+        pub fn pre_sell(&mut self) -> NonFungibleBucket {
+            // Check that no other invocation to buy or pre_sell methods happended in this
+            // transaction
+            self.check_transaction();
+
+            // Mint a withdraw badge
+            self.withdraw_badge_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(self.next_badge_id.into()),
+                WithdrawBadge {
+                }
+            )
+        }
+
+        /* This method accepts a bucket of 401k coin and swaps them for XRD.
+         * It also burns the provided withdraw badge.
+         */
+        pub fn sell(
+            &mut self,
+            // Bucket of 401k coins
+            coin_bucket: FungibleBucket,
+            // The account address of the seller
+            account: Global<Account>,
+            // The used withdraw badge
+            withdraw_badge_bucket: NonFungibleBucket,
+        ) -> Bucket {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // No 401k can be sold during the presale
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // Check that trading has started
+            self.check_trading_started();
+
+            // Check that coin_bucket contains a non zero amount of 401k coins
+            let coin_address = self.coin_manager.address();
+            assert!(
+                coin_bucket.resource_address() == coin_address,
+                "Wrong coin"
+            );
+            let coin_amount = coin_bucket.amount();
+            assert!(
+                coin_amount > Decimal::ZERO,
+                "No 401k provided"
+            );
+
+            // Check that withdraw_badge_bucket contains exacty one withdraw badge
+            assert!(
+                withdraw_badge_bucket.resource_address() == self.withdraw_badge_manager.address(),
+                "Wrong badge"
+            );
+            assert!(
+                withdraw_badge_bucket.amount() == Decimal::ONE,
+                "Exactly one withdraw badge required"
+            );
+
+            // Get existing information about the seller
+            let mut seller = self.users.get(&account).unwrap().clone();
+            
+            // Accrew him any past dividends and jackpot
+            self.accrew_dividends(&mut seller);
+            _ = self.check_won_jackpots(&mut seller, false);
+
+            // Give him back his closed dust position, if any, so that he can sell it too
+            self.reopen_dust_position(&mut seller);
+           
+            // Check that the 401k coins really came from the specified account (the ones in the
+            // AccountLocker are not in the account yet)
+            let withdrawn_401k = seller.current_bought_amount - seller.locked_amount -
+                account.balance(coin_address);
+            assert!(
+                coin_amount == withdrawn_401k,
+                "Where these 401k came from?"
+            );
+
+            // Check that the seller is not selling airdropped 401k that are not vested yet
+            assert!(
+                coin_amount <= seller.current_bought_amount - Self::unvested_amount(&mut seller),
+                "Not vested 401k can't be sold"
+            );
+
+            // Check that the sell is not too small, unless the seller is selling all of the 401k
+            // in his account
+            let residual_amount = seller.current_bought_amount - coin_amount;
+            assert!(
+                coin_amount >= self.min_sell_amount || residual_amount == seller.locked_amount,
+                "Less 401k than the minimum sell amount"
+            );
+
+            // Burn the withdraw badge and be ready to mint the next one
+            self.burn_badge(withdraw_badge_bucket);
+
+            // Sell the 401k and update the seller
+            let (xrd_bucket, price, realized_pnl, deferred_xrd_amount) =
+                self.settle_sell(&mut seller, coin_bucket);
+
+            // If requested, close the dust position left in the seller account: its dividends,
+            // jackpots and rewards go to the other holders until the seller uses it again
+            let dust_amount = residual_amount - seller.locked_amount;
+            if self.close_dust_positions && dust_amount > Decimal::ZERO &&
+                dust_amount < self.dust_threshold {
+                seller.current_bought_amount -= dust_amount;
+                seller.closed_dust_amount = dust_amount;
+                self.closed_dust_supply += dust_amount;
+            }
+
+            // Emit the SellEvent event
+            Runtime::emit_event(
+                SellEvent {
+                    account: account,
+                    price: price,
+                    sold_amount: coin_amount,
+                    current_jackpot_amount: self.current_jackpot_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                    seller_total_accrued_dividends: seller.accrued_dividends,
+                    seller_accrued_jackpot: seller.accrued_jackpot,
+                    realized_pnl: realized_pnl,
+                    seller_total_realized_pnl: seller.stats.realized_pnl,
+                    deferred_xrd_amount: deferred_xrd_amount,
+                }
+            );
+
+            // Save the updated user information and history
+            self.save_user(
+                account,
+                seller,
+            );
+            self.record_history(
+                account,
+                HistoryOperation::Sell,
+                coin_amount,
+                xrd_bucket.amount() + deferred_xrd_amount,
+                price,
+            );
+
+            xrd_bucket
+        }
+
+        // This method lets a user receive his pending dividends and jackpot shares
+        pub fn withdraw_dividends(
+            &mut self,
+            // The account to receive dividends, jackpot shares and rewards for
+            account: Global<Account>,
+        ) -> (
+            // Dividends
+            Bucket,
+            // Jackpot shares
+            Option<Bucket>,
+            // Rewards
+            Vec<Bucket>,
+        ) {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Get information about this account
+            let mut user = self.users.get(&account).expect("Account not found").clone();
+
+            // Take the pending dividends, rewards and any new or previously accrued jackpot
+            // shares
+            let (dividends_bucket, jackpot_bucket, reward_buckets, withdrawn_jackpot, withdrawn_pending_xrd) =
+                self.settle_withdraw(&mut user);
+
+            // Emit the WithdrawDividendsEvent event
+            let withdrawn_dividends = dividends_bucket.amount() - withdrawn_pending_xrd;
+            Runtime::emit_event(
+                WithdrawDividendsEvent {
+                    account: account,
+                    withdrawn_dividends: withdrawn_dividends,
+                    withdrawn_jackpot: withdrawn_jackpot,
+                    withdrawn_rewards: Self::reward_amounts(&reward_buckets),
+                    withdrawn_pending_xrd: withdrawn_pending_xrd,
+                }
+            );
+
+            // Save updated accout information
+            self.save_user(
+                account,
+                user,
+            );
+
+            // Add the withdrawals to the account history; the paid pending XRD are already in the
+            // history of the sells and jackpot withdrawals that generated them
+            if withdrawn_dividends > Decimal::ZERO {
+                self.record_history(
+                    account,
+                    HistoryOperation::DividendsWithdrawal,
+                    Decimal::ZERO,
+                    withdrawn_dividends,
+                    Decimal::ZERO,
+                );
+            }
+            if withdrawn_jackpot > Decimal::ZERO {
+                self.record_history(
+                    account,
+                    HistoryOperation::JackpotWithdrawal,
+                    Decimal::ZERO,
+                    withdrawn_jackpot,
+                    Decimal::ZERO,
+                );
+            }
+
+            (
+                dividends_bucket,
+                jackpot_bucket,
+                reward_buckets,
+            )
+        }
+
+        /* This method registers that the bought 401k have been deposited in an allowlisted
+         * component and burns the used deposit badge.
+         * It must be called by the component itself.
+         */
+        pub fn post_buy_component(
+            &mut self,
+            // Component where the 401k has been saved
+            component_address: ComponentAddress,
+            // The deposit badge
+            deposit_badge_bucket: NonFungibleBucket,
+        ) {
+            // Check that the call comes from the component itself
+            Runtime::assert_access_rule(rule!(require(global_caller(component_address))));
+
+            // Only allowlisted accounts can buy during the presale
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // Check that the deposit_badge_bucket really contains a deposit badge
+            assert!(
+                deposit_badge_bucket.resource_address() == self.deposit_badge_manager.address(),
+                "Wrong badge"
+            );
+
+            // Make sue that exacly one deposit badge is in the deposit_badge_bucket and get its
+            // NonFungibleData
+            let deposit_badge = deposit_badge_bucket.non_fungible::<DepositBadge>().data();
+
+            // Check that the component is allowed to buy 401k
+            let mut component_holder = self.component_holders.get(&component_address)
+                .expect("Component not allowed").clone();
+            assert!(
+                component_holder.allowed,
+                "Component not allowed"
+            );
+
+            // Update the holder with the bought 401k and the dividends it paid
+            self.settle_buy(&mut component_holder.holder, &deposit_badge);
+
+            // Emit the ComponentBuyEvent event
+            Runtime::emit_event(
+                ComponentBuyEvent {
+                    component_address: component_address,
+                    price: deposit_badge.price,
+                    bought_amount: deposit_badge.bought_amount,
+                    current_jackpot_amount: self.current_jackpot_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                    ath: self.ath,
+                    buyer_total_accrued_dividends: component_holder.holder.accrued_dividends,
+                    buyer_accrued_jackpot: component_holder.holder.accrued_jackpot,
+                }
+            );
+
+            // Update saved holder information
+            self.component_holders.insert(
+                component_address,
+                component_holder,
+            );
+
+            // Burn the deposit badge and be ready to mint the next one
+            self.burn_badge(deposit_badge_bucket);
+        }
+
+        /* This method accepts a bucket of 401k coin from an allowlisted component and swaps them
+         * for XRD; it also burns the provided withdraw badge.
+         * It must be called by the component itself.
+         */
+        pub fn sell_component(
+            &mut self,
+            // Bucket of 401k coins
+            coin_bucket: FungibleBucket,
+            // The component selling the 401k
+            component_address: ComponentAddress,
+            // The used withdraw badge
+            withdraw_badge_bucket: NonFungibleBucket,
+        ) -> Bucket {
+            // Check that the call comes from the component itself
+            Runtime::assert_access_rule(rule!(require(global_caller(component_address))));
+
+            // No 401k can be sold during the presale
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // Check that trading has started
+            self.check_trading_started();
+
+            // Check that coin_bucket contains a non zero amount of 401k coins
+            assert!(
+                coin_bucket.resource_address() == self.coin_manager.address(),
+                "Wrong coin"
+            );
+            let coin_amount = coin_bucket.amount();
+            assert!(
+                coin_amount > Decimal::ZERO,
+                "No 401k provided"
+            );
+
+            // Check that withdraw_badge_bucket contains exacty one withdraw badge
+            assert!(
+                withdraw_badge_bucket.resource_address() == self.withdraw_badge_manager.address(),
+                "Wrong badge"
+            );
+            assert!(
+                withdraw_badge_bucket.amount() == Decimal::ONE,
+                "Exactly one withdraw badge required"
+            );
+
+            // Get existing information about the component
+            let mut component_holder = self.component_holders.get(&component_address)
+                .expect("Component not found").clone();
+
+            // Accrew it any past dividends and jackpot
+            self.accrew_dividends(&mut component_holder.holder);
+            _ = self.check_won_jackpots(&mut component_holder.holder, false);
+
+            // The balance of a component can't be verified, at least make sure that it's not
+            // selling more 401k than it bought
+            assert!(
+                coin_amount <= component_holder.holder.current_bought_amount,
+                "Where these 401k came from?"
+            );
+
+            // Burn the withdraw badge and be ready to mint the next one
+            self.burn_badge(withdraw_badge_bucket);
+
+            // Sell the 401k and update the holder
+            let (xrd_bucket, price, realized_pnl, deferred_xrd_amount) =
+                self.settle_sell(&mut component_holder.holder, coin_bucket);
+
+            // Emit the ComponentSellEvent event
+            Runtime::emit_event(
+                ComponentSellEvent {
+                    component_address: component_address,
+                    price: price,
+                    sold_amount: coin_amount,
+                    current_jackpot_amount: self.current_jackpot_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                    seller_total_accrued_dividends: component_holder.holder.accrued_dividends,
+                    seller_accrued_jackpot: component_holder.holder.accrued_jackpot,
+                    realized_pnl: realized_pnl,
+                    seller_total_realized_pnl: component_holder.holder.stats.realized_pnl,
+                    deferred_xrd_amount: deferred_xrd_amount,
+                }
+            );
+
+            // Save the updated holder information
+            self.component_holders.insert(
+                component_address,
+                component_holder,
+            );
+
+            xrd_bucket
+        }
+
+        /* This method lets an allowlisted component receive its pending dividends and jackpot
+         * shares; it must be called by the component itself
+         */
+        pub fn withdraw_component_dividends(
+            &mut self,
+            // The component to receive dividends, jackpot shares and rewards for
+            component_address: ComponentAddress,
+        ) -> (
+            // Dividends
+            Bucket,
+            // Jackpot shares
+            Option<Bucket>,
+            // Rewards
+            Vec<Bucket>,
+        ) {
+            // Check that the call comes from the component itself
+            Runtime::assert_access_rule(rule!(require(global_caller(component_address))));
+
+            // Get information about this component
+            let mut component_holder = self.component_holders.get(&component_address)
+                .expect("Component not found").clone();
+
+            // Take the pending dividends, rewards and any new or previously accrued jackpot
+            // shares
+            let (dividends_bucket, jackpot_bucket, reward_buckets, withdrawn_jackpot, withdrawn_pending_xrd) =
+                self.settle_withdraw(&mut component_holder.holder);
+
+            // Emit the ComponentWithdrawDividendsEvent event
+            Runtime::emit_event(
+                ComponentWithdrawDividendsEvent {
+                    component_address: component_address,
+                    withdrawn_dividends: dividends_bucket.amount() - withdrawn_pending_xrd,
+                    withdrawn_jackpot: withdrawn_jackpot,
+                    withdrawn_rewards: Self::reward_amounts(&reward_buckets),
+                    withdrawn_pending_xrd: withdrawn_pending_xrd,
+                }
+            );
+
+            // Save updated holder information
+            self.component_holders.insert(
+                component_address,
+                component_holder,
+            );
+
+            (
+                dividends_bucket,
+                jackpot_bucket,
+                reward_buckets,
+            )
+        }
+
+        /* This method buys 401k coins with the provided XRD and distributes them among the
+         * recipients according to their shares.
+         * The 401k that can't be deposited are stored in the AccountLocker for the recipient to
+         * claim them later; the XRD corresponding to the unallocated share are returned to the
+         * caller.
+         */
+        pub fn airdrop(
+            &mut self,
+            // XRDs to buy 401k coins
+            mut xrd_bucket: Bucket,
+            // List of recipients. The sum of the Decimals must be 1 or less
+            recipients: IndexMap<Global<Account>, Decimal>,
+            // Optional vesting schedule for the airdropped 401k
+            vesting: Option<VestingSchedule>,
+        ) -> Bucket {
+            // Check that the XRD bucket is not empty
+            let xrd_amount = xrd_bucket.amount();
+            assert!(
+                xrd_amount > Decimal::ZERO,
+                "No XRD provided"
+            );
+
+            // Only allowlisted accounts can buy during the presale
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // Check that trading has started
+            self.check_trading_started();
+
+            // Check that the shares make sense
+            assert!(
+                !recipients.is_empty(),
+                "No recipients"
+            );
+            let mut total_share = Decimal::ZERO;
+            for share in recipients.values() {
+                assert!(
+                    *share > Decimal::ZERO,
+                    "Wrong share"
+                );
+                total_share += *share;
+            }
+            assert!(
+                total_share <= Decimal::ONE,
+                "The sum of the shares is bigger than 1"
+            );
+
+            // Check that the vesting schedule makes sense
+            Self::check_vesting_schedule(&vesting);
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Put aside the XRD of the unallocated share and the ones exceeding the supply cap,
+            // they will be refunded
+            let mut refund_bucket = xrd_bucket.take(xrd_amount * (Decimal::ONE - total_share));
+            refund_bucket.put(
+                self.take_excess_xrd(&mut xrd_bucket)
+            );
+            let xrd_amount = xrd_bucket.amount();
+
+            // Take the XRD shares to use as dividends and to add to the jackpot; they are
+            // deposited only when it's known how many 401k have actually been delivered
+            let mut dividends_bucket = xrd_bucket.take(xrd_amount * self.dividends_percentage);
+            let mut jackpot_bucket = xrd_bucket.take(xrd_amount * self.trade_jackpot_percentage());
+
+            // Deposit the remainig XRDs in the pool and mint the bought 401k coins
+            let (mut coin_bucket, price) = self.swap_xrd_for_401k(xrd_bucket);
+            let bought_amount = coin_bucket.amount();
+
+            // Mint the deposit badge
+            let deposit_badge_bucket = self.deposit_badge_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(self.next_badge_id.into()),
+                DepositBadge {
+                    bought_amount: bought_amount,
+                    price: price,
+                    dividends_amount: dividends_bucket.amount(),
+                    xrd_amount: xrd_amount,
+                    jackpot_amount: jackpot_bucket.amount(),
+                }
+            );
+
+            // For each recipient
+            for (account, share) in recipients.iter() {
+                // Compute the amount of 401k for this recipient
+                let amount = bought_amount * *share / total_share;
+                if amount == Decimal::ZERO {
+                    continue;
+                }
+
+                // Is the recipient already registered?
+                let mut recipient = match self.users.get(&account) {
+                    // If not create a new one
+                    None => self.new_user(),
+                    Some(recipient) => recipient.clone(),
+                };
+
+                // Accrew eventual past dividends and jackpots to the recipient
+                self.accrew_dividends(&mut recipient);
+                _ = self.check_won_jackpots(&mut recipient, false);
+
+                // Try sending the 401k coins to the recipient
+                let refund = deposit_badge_bucket.authorize_with_all(
+                    || {
+                        account.clone().try_deposit_or_refund(
+                            coin_bucket.take(amount).into(),
+                            None,
+                        )
+                    }
+                );
+
+                // If deposit failed, store the 401k coins in the AccountLocker; the recipient
+                // can claim them later
+                if refund.is_some() {
+                    deposit_badge_bucket.authorize_with_all(
+                        || {
+                            self.account_locker.store(
+                                *account,
+                                refund.unwrap(),
+                                false,
+                            );
+                        }
+                    );
+                    recipient.locked_amount += amount;
+
+                    // Emit the AirdropRecipientFailedEvent event
+                    Runtime::emit_event(
+                        AirdropRecipientFailedEvent {
+                            account: *account,
+                            share: *share,
+                            amount: amount,
+                        }
+                    );
+                }
+
+                // Update the statistics and the bought amount for this user (airdropped 401k
+                // are free for him) and attach the vesting schedule
+                Self::record_purchase(
+                    &mut recipient,
+                    amount,
+                    Decimal::ZERO,
+                    Decimal::ZERO,
+                );
+                recipient.current_bought_amount += amount;
+                if let Some(ref vesting) = vesting {
+                    recipient.vestings.push(
+                        Vesting {
+                            amount: amount,
+                            start: now,
+                            cliff: vesting.cliff,
+                            duration: vesting.duration,
+                        }
+                    );
+                }
+
+                // Emit the BuyEvent event
+                Runtime::emit_event(
+                    BuyEvent {
+                        account: *account,
+                        price: price,
+                        bought_amount: amount,
+                        current_jackpot_amount: self.current_jackpot_amount,
+                        global_dividends_per_401k: self.dividends_per_401k,
+                        ath: self.ath,
+                        buyer_accrued_jackpot: recipient.accrued_jackpot,
+                        buyer_total_accrued_dividends: recipient.accrued_dividends,
+                    }
+                );
+
+                // Update saved recipient information and history
+                self.save_user(
+                    *account,
+                    recipient,
+                );
+                self.record_history(
+                    *account,
+                    HistoryOperation::Airdrop,
+                    amount,
+                    Decimal::ZERO,
+                    price,
+                );
+            }
+
+            // Burn the deposit badge and get ready for minting a new one
+            deposit_badge_bucket.burn();
+            self.next_badge_id += 1;
+
+            // If some 401k coins have not been delivered (rounding excess), sell them back to the
+            // pool and refund both the XRD and the fees paid for them
+            let undelivered_amount = coin_bucket.amount();
+            if undelivered_amount > Decimal::ZERO {
+                let undelivered_fraction = undelivered_amount / bought_amount;
+                refund_bucket.put(
+                    dividends_bucket.take(dividends_bucket.amount() * undelivered_fraction)
+                );
+                refund_bucket.put(
+                    jackpot_bucket.take(jackpot_bucket.amount() * undelivered_fraction)
+                );
+                // The XRD of this airdrop have just been deposited in the pool, so they are all
+                // liquid
+                let (xrd_bucket, deferred_amount) = self.swap_401k_for_xrd(coin_bucket);
+                assert!(
+                    deferred_amount == Decimal::ZERO,
+                    "Not enough liquid XRD in the pool"
+                );
+                refund_bucket.put(xrd_bucket);
+            } else {
+                coin_bucket.burn();
+            }
+
+            // Deposit the fees paid for the delivered 401k coins
+            let dividends_amount = dividends_bucket.amount();
+            self.dividends.put(dividends_bucket);
+            let jackpot_amount = jackpot_bucket.amount();
+            self.jackpot.put(jackpot_bucket);
+            self.current_jackpot_amount += jackpot_amount;
+
+            // Compute the additional dividends amount per 401k coin
+            self.distribute_dividends(dividends_amount);
+
+            // Emit the AirdropCompletedEvent event with the new dividends_per_401k value
+            Runtime::emit_event(
+                AirdropCompletedEvent {
+                    global_dividends_per_401k: self.dividends_per_401k,
+                }
+            );
+
+            refund_bucket
+        }
+
+        /* This method lets a user receive the 401k that have been stored for him in the
+         * AccountLocker because his account refused the deposit
+         */
+        pub fn claim_locked_coins(
+            &mut self,
+            // The account to receive the stored 401k
+            account: Global<Account>,
+        ) {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Get information about this account
+            let mut user = self.users.get(&account).expect("Account not found").clone();
+            let amount = user.locked_amount;
+            assert!(
+                amount > Decimal::ZERO,
+                "Nothing to claim"
+            );
+
+            // Use a withdraw badge to take the 401k out of the AccountLocker
+            let coin_address = self.coin_manager.address();
+            let withdraw_badge_bucket = self.mint_withdraw_badge();
+            let coin_bucket = withdraw_badge_bucket.authorize_with_all(
+                || {
+                    self.account_locker.recover(
+                        account,
+                        coin_address,
+                        amount,
+                    )
+                }
+            );
+            self.burn_badge(withdraw_badge_bucket);
+
+            // Use a deposit badge to deposit the 401k in the account
+            let deposit_badge_bucket = self.mint_deposit_badge();
+            let refund = deposit_badge_bucket.authorize_with_all(
+                || {
+                    account.clone().try_deposit_or_refund(
+                        coin_bucket,
+                        None,
+                    )
+                }
+            );
+            assert!(
+                refund.is_none(),
+                "The account refused the deposit"
+            );
+            self.burn_badge(deposit_badge_bucket);
+
+            // No more 401k in the AccountLocker for this user
+            user.locked_amount = Decimal::ZERO;
+            user.stats.last_activity_at = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Emit the LockedCoinsClaimedEvent event
+            Runtime::emit_event(
+                LockedCoinsClaimedEvent {
+                    account: account,
+                    amount: amount,
+                }
+            );
+
+            // Save updated accout information
+            self.save_user(
+                account,
+                user,
+            );
+        }
+
+        /* This method moves the whole 401k position of a user from one of his accounts to
+         * another one.
+         * The 401k must be withdrawn from the source account using a withdraw badge obtained
+         * from the pre_sell method; the used withdraw badge is burned.
+         */
+        pub fn migrate_position(
+            &mut self,
+            // The account the position is moved from
+            from_account: Global<Account>,
+            // The account the position is moved to
+            to_account: Global<Account>,
+            // Bucket of all of the 401k coins in the source account
+            coin_bucket: FungibleBucket,
+            // The used withdraw badge
+            withdraw_badge_bucket: NonFungibleBucket,
+        ) {
+            // Check that the owner of both accounts has actually been involved in this
+            // transaction
+            Runtime::assert_access_rule(from_account.get_owner_role().rule);
+            Runtime::assert_access_rule(to_account.get_owner_role().rule);
+            assert!(
+                from_account != to_account,
+                "Same account"
+            );
+
+            // Check that coin_bucket contains 401k coins
+            let coin_address = self.coin_manager.address();
+            assert!(
+                coin_bucket.resource_address() == coin_address,
+                "Wrong coin"
+            );
+
+            // Check that withdraw_badge_bucket contains exacty one withdraw badge
+            assert!(
+                withdraw_badge_bucket.resource_address() == self.withdraw_badge_manager.address(),
+                "Wrong badge"
+            );
+            assert!(
+                withdraw_badge_bucket.amount() == Decimal::ONE,
+                "Exactly one withdraw badge required"
+            );
+
+            // Get existing information about both accounts
+            let mut from_user = self.users.get(&from_account).expect("Account not found").clone();
+            let mut to_user = match self.users.get(&to_account) {
+                None => self.new_user(),
+                Some(to_user) => to_user.clone(),
+            };
+
+            // Accrew them any past dividends and jackpot
+            self.accrew_dividends(&mut from_user);
+            _ = self.check_won_jackpots(&mut from_user, false);
+            self.accrew_dividends(&mut to_user);
+            _ = self.check_won_jackpots(&mut to_user, false);
+
+            // Reopen their closed dust positions, if any, so that they are migrated too
+            self.reopen_dust_position(&mut from_user);
+            self.reopen_dust_position(&mut to_user);
+
+            // Check that all of the 401k coins in the source account have been withdrawn
+            let coin_amount = coin_bucket.amount();
+            assert!(
+                from_account.balance(coin_address) == Decimal::ZERO &&
+                    coin_amount == from_user.current_bought_amount - from_user.locked_amount,
+                "The whole position must be migrated"
+            );
+
+            // Burn the withdraw badge and be ready to mint the next one
+            self.burn_badge(withdraw_badge_bucket);
+
+            // If the source account has 401k in the AccountLocker, move them to the destination
+            // account
+            let locked_amount = from_user.locked_amount;
+            if locked_amount > Decimal::ZERO {
+                let withdraw_badge_bucket = self.mint_withdraw_badge();
+                let locked_bucket = withdraw_badge_bucket.authorize_with_all(
+                    || {
+                        self.account_locker.recover(
+                            from_account,
+                            coin_address,
+                            locked_amount,
+                        )
+                    }
+                );
+                self.burn_badge(withdraw_badge_bucket);
+
+                let deposit_badge_bucket = self.mint_deposit_badge();
+                deposit_badge_bucket.authorize_with_all(
+                    || {
+                        self.account_locker.store(
+                            to_account,
+                            locked_bucket,
+                            false,
+                        );
+                    }
+                );
+                self.burn_badge(deposit_badge_bucket);
+            }
+
+            // Send the 401k to the destination account; if the deposit fails, they are stored
+            // in the AccountLocker
+            let stored_amount = locked_amount + self.deposit_or_lock(to_account, coin_bucket);
+
+            // Merge the source user into the destination one; both are up to date with dividends
+            // and jackpots now
+            Self::merge_stats(
+                &mut to_user,
+                &from_user,
+            );
+            let migrated_amount = from_user.current_bought_amount;
+            to_user.current_bought_amount += migrated_amount;
+            to_user.accrued_dividends += from_user.accrued_dividends;
+            to_user.accrued_jackpot += from_user.accrued_jackpot;
+            to_user.pending_xrd_amount += from_user.pending_xrd_amount;
+            Self::move_accrued_rewards(
+                &mut from_user,
+                &mut to_user,
+                Decimal::ONE,
+            );
+            to_user.current_jackpot_number = self.current_jackpot_number;
+            to_user.locked_amount += stored_amount;
+            to_user.vestings.append(&mut from_user.vestings);
+
+            // Move the unclaimed airdrop campaign allocations too
+            if let Some(mut from_allocations) = self.airdrop_allocations.remove(&from_account) {
+                let mut to_allocations = match self.airdrop_allocations.get(&to_account) {
+                    None => vec![],
+                    Some(to_allocations) => to_allocations.clone(),
+                };
+                to_allocations.append(&mut from_allocations);
+                self.airdrop_allocations.insert(
+                    to_account,
+                    to_allocations,
+                );
+            }
+
+            // Record the migration in the destination account history; the source account keeps
+            // its history, the Migration entry points to it
+            self.record_history(
+                to_account,
+                HistoryOperation::Migration(from_account),
+                migrated_amount,
+                Decimal::ZERO,
+                Decimal::ZERO,
+            );
+
+            // Emit the PositionMigratedEvent event
+            Runtime::emit_event(
+                PositionMigratedEvent {
+                    from_account: from_account,
+                    to_account: to_account,
+                    migrated_amount: migrated_amount,
+                    to_account_amount: to_user.current_bought_amount,
+                    to_account_total_accrued_dividends: to_user.accrued_dividends,
+                    to_account_accrued_jackpot: to_user.accrued_jackpot,
+                }
+            );
+
+            // Save updated information; the source account is left with an empty position
+            let empty_user = self.new_user();
+            self.save_user(
+                from_account,
+                empty_user,
+            );
+            self.save_user(
+                to_account,
+                to_user,
+            );
+        }
+
+        /* This method moves 401k coins from a user account to another user account, charging
+         * the transfer fees.
+         * The 401k must be withdrawn from the sender account using a withdraw badge obtained
+         * from the pre_sell method; the used withdraw badge is burned.
+         */
+        pub fn transfer(
+            &mut self,
+            // The sender account
+            from_account: Global<Account>,
+            // The recipient account
+            to_account: Global<Account>,
+            // Bucket of 401k coins to transfer
+            mut coin_bucket: FungibleBucket,
+            // The used withdraw badge
+            withdraw_badge_bucket: NonFungibleBucket,
+        ) {
+            // Check that the sender account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(from_account.get_owner_role().rule);
+            assert!(
+                from_account != to_account,
+                "Same account"
+            );
+
+            // No 401k can be transferred during the presale, because the transfer fee is sold
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // For the same reason, check that trading has started
+            self.check_trading_started();
+
+            // Check that coin_bucket contains a non zero amount of 401k coins
+            let coin_address = self.coin_manager.address();
+            assert!(
+                coin_bucket.resource_address() == coin_address,
+                "Wrong coin"
+            );
+            let coin_amount = coin_bucket.amount();
+            assert!(
+                coin_amount > Decimal::ZERO,
+                "No 401k provided"
+            );
+
+            // Check that withdraw_badge_bucket contains exacty one withdraw badge
+            assert!(
+                withdraw_badge_bucket.resource_address() == self.withdraw_badge_manager.address(),
+                "Wrong badge"
+            );
+            assert!(
+                withdraw_badge_bucket.amount() == Decimal::ONE,
+                "Exactly one withdraw badge required"
+            );
+
+            // Get existing information about both accounts
+            let mut sender = self.users.get(&from_account).expect("Account not found").clone();
+            let mut recipient = match self.users.get(&to_account) {
+                None => self.new_user(),
+                Some(recipient) => recipient.clone(),
+            };
+
+            // Accrew them any past dividends and jackpot
+            self.accrew_dividends(&mut sender);
+            _ = self.check_won_jackpots(&mut sender, false);
+            self.accrew_dividends(&mut recipient);
+            _ = self.check_won_jackpots(&mut recipient, false);
+
+            // Reopen their closed dust positions, if any
+            self.reopen_dust_position(&mut sender);
+            self.reopen_dust_position(&mut recipient);
+
+            // Check that the 401k coins really came from the sender account (the ones in the
+            // AccountLocker are not in the account yet)
+            let withdrawn_401k = sender.current_bought_amount - sender.locked_amount -
+                from_account.balance(coin_address);
+            assert!(
+                coin_amount == withdrawn_401k,
+                "Where these 401k came from?"
+            );
+
+            // Check that the sender is not transferring airdropped 401k that are not vested yet
+            assert!(
+                coin_amount <= sender.current_bought_amount - Self::unvested_amount(&mut sender),
+                "Not vested 401k can't be transferred"
+            );
+
+            // Burn the withdraw badge and be ready to mint the next one
+            self.burn_badge(withdraw_badge_bucket);
+
+            // Update the owned coins amount of both users before distributing the fee, so that
+            // the dividends it pays are split among the 401k that remain in circulation, the
+            // transferred ones included. The transferred 401k are free for the recipient
+            let fee_percentage = self.transfer_dividends_percentage + self.transfer_jackpot_percentage;
+            let fee_amount = coin_amount * fee_percentage;
+            let transferred_amount = coin_amount - fee_amount;
+            sender.current_bought_amount -= coin_amount;
+            Self::record_purchase(
+                &mut recipient,
+                transferred_amount,
+                Decimal::ZERO,
+                Decimal::ZERO,
+            );
+            recipient.current_bought_amount += transferred_amount;
+
+            // Sell the 401k of the transfer fee and split the XRD between dividends and jackpot.
+            // The transfer fee replaces the trade fees: the sale of the fee 401k doesn't pay the
+            // dividends and jackpot fees of a normal sell
+            let mut fee_xrd_amount = Decimal::ZERO;
+            if fee_amount > Decimal::ZERO {
+                let (mut xrd_bucket, deferred_amount) = self.swap_401k_for_xrd(
+                    coin_bucket.take(fee_amount)
+                );
+                fee_xrd_amount = xrd_bucket.amount() + deferred_amount;
+                sender.stats.total_fees_paid += fee_xrd_amount;
+
+                // Split the XRDs between the jackpot and the dividends
+                let jackpot_amount = fee_xrd_amount * self.transfer_jackpot_percentage / fee_percentage;
+                self.pay_sale_fees(
+                    &mut xrd_bucket,
+                    fee_xrd_amount - jackpot_amount,
+                    jackpot_amount,
+                );
+                xrd_bucket.drop_empty();
+            }
+
+            // Send the remaining 401k to the recipient account; if the deposit fails, they are
+            // stored in the AccountLocker
+            recipient.locked_amount += self.deposit_or_lock(to_account, coin_bucket);
+
+            // Accrew both users their share of the dividends paid by the fee
+            self.accrew_dividends(&mut sender);
+            self.accrew_dividends(&mut recipient);
+
+            // Update the statistics of the sender; the transferred 401k don't change the average
+            // cost of the ones left to him
+            sender.stats.last_activity_at = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+
+            // Emit the TransferEvent event
+            Runtime::emit_event(
+                TransferEvent {
+                    from_account: from_account,
+                    to_account: to_account,
+                    sent_amount: coin_amount,
+                    received_amount: transferred_amount,
+                    current_jackpot_amount: self.current_jackpot_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                }
+            );
+
+            // Save updated users information and history
+            self.save_user(
+                from_account,
+                sender,
+            );
+            self.save_user(
+                to_account,
+                recipient,
+            );
+            self.record_history(
+                from_account,
+                HistoryOperation::TransferSent,
+                coin_amount,
+                fee_xrd_amount,
+                Decimal::ZERO,
+            );
+            self.record_history(
+                to_account,
+                HistoryOperation::TransferReceived,
+                transferred_amount,
+                Decimal::ZERO,
+                Decimal::ZERO,
+            );
+        }
+
+        /* This method creates an airdrop campaign: it buys the 401k coins needed for the
+         * specified allocations and keeps them in the component until each recipient claims his
+         * allocation or the campaign expires.
+         * The XRD exceeding the cost of the 401k are returned to the caller.
+         */
+        pub fn create_airdrop_campaign(
+            &mut self,
+            // XRDs to buy 401k coins
+            xrd_bucket: Bucket,
+            // Amount of 401k allocated to each recipient
+            allocations: IndexMap<Global<Account>, Decimal>,
+            // When the campaign expires (seconds since unix epoch)
+            expires_at: i64,
+            // Optional vesting schedule for the claimed 401k
+            vesting: Option<VestingSchedule>,
+        ) -> Bucket {
+            // Check that input parameters make sense
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            assert!(
+                expires_at > now,
+                "Wrong expires_at"
+            );
+            Self::check_vesting_schedule(&vesting);
+
+            // Only allowlisted accounts can buy during the presale
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // Check that trading has started
+            self.check_trading_started();
+            assert!(
+                !allocations.is_empty(),
+                "No recipients"
+            );
+            let mut total_amount = Decimal::ZERO;
+            for amount in allocations.values() {
+                assert!(
+                    *amount > Decimal::ZERO,
+                    "Wrong allocation"
+                );
+                total_amount += *amount;
+            }
+
+            // Take the XRD needed to buy the allocated 401k, the remaining ones will be refunded
+            let xrd_amount = self.xrd_needed_for(
+                total_amount,
+                RoundingMode::AwayFromZero,
+            );
+            let mut refund_bucket = xrd_bucket;
+            assert!(
+                refund_bucket.amount() >= xrd_amount,
+                "Not enough XRD provided"
+            );
+            let mut xrd_bucket = refund_bucket.take(xrd_amount);
+
+            // Take the XRD share to use as dividends
+            let dividends_amount = xrd_amount * self.dividends_percentage;
+            self.dividends.put(
+                xrd_bucket.take(dividends_amount)
+            );
+
+            // Take the XRD share to add to the jackpot
+            let jackpot_amount = xrd_amount * self.trade_jackpot_percentage();
+            self.jackpot.put(
+                xrd_bucket.take(jackpot_amount)
+            );
+            self.current_jackpot_amount += jackpot_amount;
+
+            // Deposit the remainig XRDs in the pool and mint the bought 401k coins
+            let (coin_bucket, price) = self.swap_xrd_for_401k(xrd_bucket);
+            let bought_amount = coin_bucket.amount();
+
+            // The campaign holds the bought 401k: compute the new global dividends amount per
+            // 401k coin and accrew the campaign its own share of the dividends it paid
+            let mut holder = self.new_user();
+            self.distribute_dividends(dividends_amount);
+            holder.current_bought_amount = bought_amount;
+            self.accrew_dividends(&mut holder);
+
+            // Use a deposit badge to put the 401k in the campaign vault
+            let deposit_badge_bucket = self.mint_deposit_badge();
+            let coins = deposit_badge_bucket.authorize_with_all(
+                || {
+                    FungibleVault::with_bucket(coin_bucket)
+                }
+            );
+            self.burn_badge(deposit_badge_bucket);
+
+            // Save the campaign
+            let campaign_id = self.next_campaign_id;
+            self.next_campaign_id += 1;
+            self.airdrop_campaigns.insert(
+                campaign_id,
+                AirdropCampaign {
+                    coins: coins,
+                    holder: holder,
+                    expires_at: expires_at,
+                    price: price,
+                    created_at: now,
+                    vesting: vesting,
+                }
+            );
+
+            // Add the allocations to the ones each recipient can claim
+            for (account, amount) in allocations.iter() {
+                let mut account_allocations = match self.airdrop_allocations.get(account) {
+                    None => vec![],
+                    Some(account_allocations) => account_allocations.clone(),
+                };
+                account_allocations.push(
+                    AirdropAllocation {
+                        campaign_id: campaign_id,
+                        amount: *amount,
+                    }
+                );
+                self.airdrop_allocations.insert(
+                    *account,
+                    account_allocations,
+                );
+            }
+
+            // Emit the AirdropCampaignCreatedEvent event
+            Runtime::emit_event(
+                AirdropCampaignCreatedEvent {
+                    campaign_id: campaign_id,
+                    price: price,
+                    bought_amount: bought_amount,
+                    expires_at: expires_at,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                }
+            );
+
+            refund_bucket
+        }
+
+        /* This method lets a user receive his allocations in all of the active airdrop
+         * campaigns, together with the dividends and jackpot shares they accrued so far.
+         * Allocations in expired campaigns are discarded; the method fails if none of the
+         * allocations can be claimed.
+         */
+        pub fn claim_airdrop(
+            &mut self,
+            // The account to receive the 401k
+            account: Global<Account>,
+        ) {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Get the allocations of this account and remove them
+            let allocations = self.airdrop_allocations.remove(&account).expect("Nothing to claim");
+
+            // Is the recipient already registered?
+            let mut user = match self.users.get(&account) {
+                // If not create a new one
+                None => self.new_user(),
+                Some(user) => user.clone(),
+            };
+
+            // Accrew eventual past dividends and jackpots to the recipient
+            self.accrew_dividends(&mut user);
+            _ = self.check_won_jackpots(&mut user, false);
+
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            let mut coin_bucket = FungibleBucket::new(self.coin_manager.address());
+            let mut expired_allocations: usize = 0;
+
+            // For each allocation
+            for allocation in allocations.iter() {
+                // Get the accounting of the unclaimed 401k in this campaign, skip the expired ones
+                let campaign = match self.airdrop_campaigns.get(&allocation.campaign_id) {
+                    Some(campaign) if campaign.expires_at > now => Some((
+                        campaign.holder.clone(),
+                        campaign.price,
+                        campaign.created_at,
+                        campaign.vesting.clone(),
+                    )),
+                    _ => None,
+                };
+                let (mut holder, price, created_at, vesting) = match campaign {
+                    Some(campaign) => campaign,
+                    None => {
+                        expired_allocations += 1;
+                        continue;
+                    },
+                };
+
+                // Accrew the dividends and jackpots of the unclaimed 401k
+                self.accrew_dividends(&mut holder);
+                _ = self.check_won_jackpots(&mut holder, false);
+
+                // Compute the claimed amount
+                let amount = allocation.amount.min(holder.current_bought_amount);
+                if amount == Decimal::ZERO {
+                    continue;
+                }
+
+                // Move the claimed 401k and their share of the accrued dividends and jackpots
+                // from the campaign to the user
+                let claimed_fraction = amount / holder.current_bought_amount;
+                let dividends_amount = holder.accrued_dividends * claimed_fraction;
+                let jackpot_amount = holder.accrued_jackpot * claimed_fraction;
+                holder.current_bought_amount -= amount;
+                holder.accrued_dividends -= dividends_amount;
+                holder.accrued_jackpot -= jackpot_amount;
+                Self::record_purchase(
+                    &mut user,
+                    amount,
+                    Decimal::ZERO,
+                    Decimal::ZERO,
+                );
+                user.current_bought_amount += amount;
+                user.accrued_dividends += dividends_amount;
+                user.accrued_jackpot += jackpot_amount;
+                if let Some(vesting) = vesting {
+                    user.vestings.push(
+                        Vesting {
+                            amount: amount,
+                            start: created_at,
+                            cliff: vesting.cliff,
+                            duration: vesting.duration,
+                        }
+                    );
+                }
+                Self::move_accrued_rewards(
+                    &mut holder,
+                    &mut user,
+                    claimed_fraction,
+                );
+
+                // Use a withdraw badge to take the 401k out of the campaign vault and save the
+                // updated campaign accounting
+                let withdraw_badge_bucket = self.mint_withdraw_badge();
+                {
+                    let mut campaign = self.airdrop_campaigns.get_mut(&allocation.campaign_id).unwrap();
+                    campaign.holder = holder;
+                    coin_bucket.put(
+                        withdraw_badge_bucket.authorize_with_all(
+                            || {
+                                campaign.coins.take(amount)
+                            }
+                        )
+                    );
+                }
+                self.burn_badge(withdraw_badge_bucket);
+
+                // Emit the AirdropClaimedEvent event and add the claim to the account history
+                Runtime::emit_event(
+                    AirdropClaimedEvent {
+                        campaign_id: allocation.campaign_id,
+                        account: account,
+                        amount: amount,
+                    }
+                );
+                self.record_history(
+                    account,
+                    HistoryOperation::Airdrop,
+                    amount,
+                    Decimal::ZERO,
+                    price,
+                );
+            }
+
+            // Don't let the claim succeed without delivering anything
+            assert!(
+                expired_allocations < allocations.len(),
+                "All of the allocated airdrop campaigns have expired"
+            );
+            assert!(
+                coin_bucket.amount() > Decimal::ZERO,
+                "Nothing to claim"
+            );
+
+            // Send the 401k to the account; if the deposit fails, they are stored in the
+            // AccountLocker
+            user.locked_amount += self.deposit_or_lock(account, coin_bucket);
+
+            // Save updated user information
+            self.save_user(
+                account,
+                user,
+            );
+        }
+
+        /* This method closes an expired airdrop campaign: the unclaimed 401k are burned, so that
+         * their XRD stay in the pool, and the dividends and jackpots they accrued are given back
+         * to the holders and the current jackpot
+         */
+        pub fn close_airdrop_campaign(
+            &mut self,
+            // Id of the campaign to close
+            campaign_id: u32,
+        ) {
+            // Check that the campaign exists and has expired, then get the accounting of its
+            // unclaimed 401k
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            let mut holder = {
+                let campaign = self.airdrop_campaigns.get(&campaign_id).expect("Campaign not found");
+                assert!(
+                    campaign.expires_at <= now,
+                    "Campaign not expired yet"
+                );
+                assert!(
+                    campaign.coins.amount() > Decimal::ZERO,
+                    "Campaign already closed"
+                );
+                campaign.holder.clone()
+            };
+
+            // Accrew the dividends and jackpots of the unclaimed 401k
+            self.accrew_dividends(&mut holder);
+            _ = self.check_won_jackpots(&mut holder, false);
+
+            // Use a withdraw badge to take all of the 401k out of the campaign vault and reset
+            // the campaign accounting
+            let empty_holder = self.new_user();
+            let withdraw_badge_bucket = self.mint_withdraw_badge();
+            let coin_bucket = {
+                let mut campaign = self.airdrop_campaigns.get_mut(&campaign_id).unwrap();
+                campaign.holder = empty_holder;
+                let amount = campaign.coins.amount();
+                withdraw_badge_bucket.authorize_with_all(
+                    || {
+                        campaign.coins.take(amount)
+                    }
+                )
+            };
+            self.burn_badge(withdraw_badge_bucket);
+
+            // Burn the unclaimed 401k
+            let burned_amount = coin_bucket.amount();
+            coin_bucket.burn();
+
+            // Give the dividends and rewards accrued by the unclaimed 401k to the remaining
+            // holders and put the jackpots back in the current one
+            self.dividends_liability =
+                (self.dividends_liability - holder.accrued_dividends).max(Decimal::ZERO);
+            self.distribute_dividends(holder.accrued_dividends);
+            let current_supply = self.earning_supply();
+            for (resource_address, holder_reward) in holder.rewards.iter() {
+                let mut reward_token = self.reward_tokens.get_mut(resource_address).unwrap();
+                match current_supply > Decimal::ZERO {
+                    true => reward_token.reward_per_401k +=
+                        PreciseDecimal::from(holder_reward.accrued_amount) / current_supply,
+                    false => reward_token.liability =
+                        (reward_token.liability - holder_reward.accrued_amount).max(Decimal::ZERO),
+                }
+            }
+            self.jackpot_liability = (self.jackpot_liability - holder.accrued_jackpot).max(Decimal::ZERO);
+            self.current_jackpot_amount += holder.accrued_jackpot;
+
+            // Emit the AirdropCampaignClosedEvent event
+            Runtime::emit_event(
+                AirdropCampaignClosedEvent {
+                    campaign_id: campaign_id,
+                    burned_amount: burned_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                }
+            );
+        }
+
+        /* The owner can use this method to open a batch auction before the trading start: during
+         * the commitment window accounts commit XRD, then all of them get 401k at the same
+         * clearing price
+         */
+        pub fn open_auction(
+            &mut self,
+            // When the commitment window closes (seconds since unix epoch)
+            ends_at: i64,
+        ) {
+            // Check that input parameters make sense
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            assert!(
+                ends_at > now && ends_at <= self.trading_starts_at,
+                "Wrong ends_at"
+            );
+
+            // Check that the auction is the first sale of 401k
+            assert!(
+                self.auction.is_none(),
+                "Auction already opened"
+            );
+            assert!(
+                self.presale.is_none(),
+                "Auction and presale can't be combined"
+            );
+            assert!(
+                self.coin_manager.total_supply().unwrap() == Decimal::ZERO,
+                "401k already sold"
+            );
+
+            self.auction = Some(
+                Auction {
+                    ends_at: ends_at,
+                    xrd: Vault::new(XRD),
+                    total_committed: Decimal::ZERO,
+                    unclaimed_committed: Decimal::ZERO,
+                    closed: false,
+                    coins: FungibleVault::new(self.coin_manager.address()),
+                    price: Decimal::ZERO,
+                    fees_amount: Decimal::ZERO,
+                    holder: self.new_user(),
+                }
+            );
+        }
+
+        // This method lets an account commit XRD to the opening batch auction
+        pub fn commit_to_auction(
+            &mut self,
+            // The account that will receive the 401k
+            account: Global<Account>,
+            // XRD to commit
+            xrd_bucket: Bucket,
+        ) {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Check that the auction is open
+            let mut auction = self.auction.take().expect("No auction");
+            assert!(
+                Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch < auction.ends_at,
+                "Auction ended"
+            );
+
+            // Check that the XRD bucket is not empty
+            assert!(
+                xrd_bucket.resource_address() == XRD,
+                "Wrong coin"
+            );
+            let amount = xrd_bucket.amount();
+            assert!(
+                amount > Decimal::ZERO,
+                "No XRD provided"
+            );
+
+            // Take the XRD and add them to the account commitment
+            auction.xrd.put(xrd_bucket);
+            auction.total_committed += amount;
+            auction.unclaimed_committed += amount;
+            let committed = match self.auction_commitments.get(&account) {
+                None => Decimal::ZERO,
+                Some(committed) => *committed,
+            };
+            self.auction_commitments.insert(
+                account,
+                committed + amount,
+            );
+
+            // Emit the AuctionCommittedEvent event
+            Runtime::emit_event(
+                AuctionCommittedEvent {
+                    account: account,
+                    amount: amount,
+                    total_committed: auction.total_committed,
+                }
+            );
+
+            self.auction = Some(auction);
+        }
+
+        /* This method closes the opening batch auction after the commitment window: all of the
+         * committed XRD buy 401k in a single operation; the XRD exceeding the supply cap will be
+         * refunded to the committers
+         */
+        pub fn close_auction(&mut self) {
+            // Check that the commitment window is over
+            let mut auction = self.auction.take().expect("No auction");
+            assert!(
+                Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch >= auction.ends_at,
+                "Auction not ended yet"
+            );
+            assert!(
+                !auction.closed,
+                "Auction already closed"
+            );
+            auction.closed = true;
+
+            // Nothing to buy if nobody committed
+            if auction.total_committed == Decimal::ZERO {
+                self.auction = Some(auction);
+                return;
+            }
+
+            // Take all of the committed XRD, put back the ones exceeding the supply cap
+            let mut xrd_bucket = auction.xrd.take_all();
+            let unfilled_bucket = self.take_excess_xrd(&mut xrd_bucket);
+            let unfilled_xrd_amount = unfilled_bucket.amount();
+            auction.xrd.put(unfilled_bucket);
+            let xrd_amount = xrd_bucket.amount();
+
+            // Take the XRD share to use as dividends
+            let dividends_amount = xrd_amount * self.dividends_percentage;
+            self.dividends.put(
+                xrd_bucket.take(dividends_amount)
+            );
+
+            // Take the XRD share to add to the jackpot
+            let jackpot_amount = xrd_amount * self.trade_jackpot_percentage();
+            self.jackpot.put(
+                xrd_bucket.take(jackpot_amount)
+            );
+            self.current_jackpot_amount += jackpot_amount;
+
+            // Deposit the remainig XRDs in the pool and mint the bought 401k coins
+            let (coin_bucket, price) = self.swap_xrd_for_401k(xrd_bucket);
+            let bought_amount = coin_bucket.amount();
+
+            // The auction holds the bought 401k: compute the new global dividends amount per
+            // 401k coin and accrew the auction its own share of the dividends it paid
+            self.distribute_dividends(dividends_amount);
+            auction.holder.current_bought_amount = bought_amount;
+            self.accrew_dividends(&mut auction.holder);
+            auction.price = price;
+            auction.fees_amount = dividends_amount + jackpot_amount;
+
+            // Use a deposit badge to put the 401k in the auction vault
+            let deposit_badge_bucket = self.mint_deposit_badge();
+            deposit_badge_bucket.authorize_with_all(
+                || {
+                    auction.coins.put(coin_bucket);
+                }
+            );
+            self.burn_badge(deposit_badge_bucket);
+
+            // Emit the AuctionClosedEvent event
+            Runtime::emit_event(
+                AuctionClosedEvent {
+                    price: price,
+                    bought_amount: bought_amount,
+                    filled_xrd_amount: xrd_amount,
+                    unfilled_xrd_amount: unfilled_xrd_amount,
+                    global_dividends_per_401k: self.dividends_per_401k,
+                }
+            );
+
+            self.auction = Some(auction);
+        }
+
+        /* This method lets an account receive its share of the 401k bought by the opening batch
+         * auction, with the dividends and jackpots they accrued, and of the unfilled XRD
+         */
+        pub fn claim_auction(
+            &mut self,
+            // The account that committed XRD
+            account: Global<Account>,
+        ) -> Bucket {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Get the commitment of this account and remove it
+            let committed = self.auction_commitments.remove(&account).expect("Nothing to claim");
+
+            // Check that the auction has been closed
+            let mut auction = self.auction.take().expect("No auction");
+            assert!(
+                auction.closed,
+                "Auction not closed yet"
+            );
+
+            // Is the buyer already registered?
+            let mut user = match self.users.get(&account) {
+                // If not create a new one
+                None => self.new_user(),
+                Some(user) => user.clone(),
+            };
+
+            // Accrew eventual past dividends and jackpots to the buyer and the unclaimed 401k
+            self.accrew_dividends(&mut user);
+            _ = self.check_won_jackpots(&mut user, false);
+            self.accrew_dividends(&mut auction.holder);
+            _ = self.check_won_jackpots(&mut auction.holder, false);
+
+            // Compute the account share of the unclaimed 401k and unfilled XRD; the last one to
+            // claim gets all of the remainders
+            let claimed_fraction = committed / auction.unclaimed_committed;
+            let (amount, refunded_xrd_amount) = match committed == auction.unclaimed_committed {
+                true => (auction.coins.amount(), auction.xrd.amount()),
+                false => (
+                    auction.coins.amount() * claimed_fraction,
+                    auction.xrd.amount() * claimed_fraction,
+                ),
+            };
+            auction.unclaimed_committed -= committed;
+
+            // Move the claimed 401k and their share of the accrued dividends and jackpots from
+            // the auction to the user; all of the buyers pay the same price
+            let dividends_amount = auction.holder.accrued_dividends * claimed_fraction;
+            let jackpot_amount = auction.holder.accrued_jackpot * claimed_fraction;
+            auction.holder.current_bought_amount -= amount;
+            auction.holder.accrued_dividends -= dividends_amount;
+            auction.holder.accrued_jackpot -= jackpot_amount;
+            Self::record_purchase(
+                &mut user,
+                amount,
+                committed - refunded_xrd_amount,
+                auction.fees_amount * committed / auction.total_committed,
+            );
+            user.current_bought_amount += amount;
+            user.accrued_dividends += dividends_amount;
+            user.accrued_jackpot += jackpot_amount;
+            Self::move_accrued_rewards(
+                &mut auction.holder,
+                &mut user,
+                claimed_fraction,
+            );
+
+            // Use a withdraw badge to take the 401k out of the auction vault
+            let withdraw_badge_bucket = self.mint_withdraw_badge();
+            let coin_bucket = withdraw_badge_bucket.authorize_with_all(
+                || {
+                    auction.coins.take(amount)
+                }
+            );
+            self.burn_badge(withdraw_badge_bucket);
+            let refund_bucket = auction.xrd.take(refunded_xrd_amount);
+            let price = auction.price;
+            self.auction = Some(auction);
+
+            // Send the 401k to the account; if the deposit fails, they are stored in the
+            // AccountLocker
+            user.locked_amount += self.deposit_or_lock(account, coin_bucket);
+
+            // Emit the AuctionClaimedEvent event
+            Runtime::emit_event(
+                AuctionClaimedEvent {
+                    account: account,
+                    amount: amount,
+                    refunded_xrd_amount: refunded_xrd_amount,
+                }
+            );
+
+            // Save updated user information and history
+            self.save_user(
+                account,
+                user,
+            );
+            self.record_history(
+                account,
+                HistoryOperation::Buy,
+                amount,
+                committed - refunded_xrd_amount,
+                price,
+            );
+
+            refund_bucket
+        }
+
+        /* The owner can use this method to graduate the club when the bonding curve pool is big
+         * enough: part of the bonding curve pool XRD and newly minted 401k at the current spot
+         * price seed a TwoResourcePool, the rest of the pool XRD goes to the treasury.
+         * The bonding curve closes: from now on all of the buys and sells happen on the
+         * TwoResourcePool, that is a component holder earning dividends, jackpots and rewards
+         * that are compounded in it. The pool units are locked in the component.
+         */
+        pub fn graduate(&mut self) {
+            // Check that graduation is enabled and didn't happen yet
+            assert!(
+                self.graduation_xrd_percentage > Decimal::ZERO,
+                "Graduation not enabled"
+            );
+            assert!(
+                self.graduation_pool.is_none(),
+                "Already graduated"
+            );
+
+            // Check that trading started, the opening auction is closed and the presale is over
+            self.check_trading_started();
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            // Check that the bonding curve pool is big enough
+            let current_supply = self.coin_manager.total_supply().unwrap();
+            assert!(
+                current_supply >= self.curve_change_supply ||
+                    (self.graduation_xrd_amount > Decimal::ZERO &&
+                    self.pool_xrd_amount() >= self.graduation_xrd_amount),
+                "Graduation threshold not reached"
+            );
+
+            // All of the pool XRD must be liquid
+            assert!(
+                self.pool_stake.staked + self.pool_stake.unstaking == Decimal::ZERO,
+                "Unstake the pool XRD before graduating"
+            );
+
+            // Mint the 401k matching the XRD moved from the bonding curve pool at the spot price
+            let price = self.spot_price();
+            let xrd_amount = self.pool.amount() * self.graduation_xrd_percentage;
+            let coin_amount = xrd_amount / price;
+            assert!(
+                coin_amount > Decimal::ZERO,
+                "No XRD in the bonding curve pool"
+            );
+            assert!(
+                current_supply + coin_amount <= self.supply_cap,
+                "Not enough 401k available below the supply cap"
+            );
+            let xrd_bucket = self.pool.take(xrd_amount);
+            let coin_bucket = self.coin_manager.mint(coin_amount);
+
+            // The rest of the bonding curve pool XRD goes to the treasury
+            let treasury_xrd_amount = self.pool.amount();
+            self.treasury.put(
+                self.pool.take_all()
+            );
+
+            // Create the TwoResourcePool; only this component can contribute to it
+            let coin_address = self.coin_manager.address();
+            let mut pool = Blueprint::<TwoResourcePool>::instantiate(
+                OwnerRole::None,
+                rule!(require(global_caller(Runtime::global_address()))),
+                (XRD, coin_address),
+                None,
+            );
+            let pool_address = pool.address();
+
+            // Put the XRD and the 401k in the TwoResourcePool using a deposit badge
+            let deposit_badge_bucket = self.mint_deposit_badge();
+            let (pool_units_bucket, change) = deposit_badge_bucket.authorize_with_all(
+                || {
+                    pool.contribute(
+                        (xrd_bucket.as_fungible(), coin_bucket)
+                    )
+                }
+            );
+            self.burn_badge(deposit_badge_bucket);
+
+            // The first contribution to an empty pool should leave no change; if it does, put
+            // it back in the TwoResourcePool
+            if let Some(change) = change {
+                let deposit_badge_bucket = self.mint_deposit_badge();
+                deposit_badge_bucket.authorize_with_all(
+                    || {
+                        pool.protected_deposit(change)
+                    }
+                );
+                self.burn_badge(deposit_badge_bucket);
             }
 
-            // Emit the SellEvent event
+            // Register the TwoResourcePool as a component holder of its 401k
+            self.add_component_holder(pool_address);
+            let mut component_holder = self.component_holders.get(&pool_address).unwrap().clone();
+            component_holder.holder.current_bought_amount = coin_amount;
+            self.component_holders.insert(
+                pool_address,
+                component_holder,
+            );
+
+            // Lock the pool units in the component
+            let pool_units_amount = pool_units_bucket.amount();
+            self.pool_units = Some(FungibleVault::with_bucket(pool_units_bucket));
+            self.graduation_pool = Some(pool);
+
+            // Emit the GraduatedEvent event
             Runtime::emit_event(
-                SellEvent {
-                    account: account,
+                GraduatedEvent {
+                    pool_address: pool_address,
+                    xrd_amount: xrd_amount,
+                    coin_amount: coin_amount,
                     price: price,
-                    sold_amount: coin_amount,
-                    current_jackpot_amount: self.current_jackpot_amount,
-                    global_dividends_per_401k: self.dividends_per_401k,
-                    seller_total_accrued_dividends: seller.accrued_dividends,
-                    seller_accrued_jackpot: seller.accrued_jackpot,
+                    treasury_xrd_amount: treasury_xrd_amount,
+                    pool_units_amount: pool_units_amount,
                 }
             );
+        }
 
-            // Save the updated user information
-            self.users.insert(
-                account,
-                seller,
+        /* Sponsors can use this method to donate XRD to the current 401k holders; the donation
+         * raises the dividends per 401k without buying any 401k
+         */
+        pub fn donate_dividends(
+            &mut self,
+            // The donated XRD
+            xrd_bucket: Bucket,
+            // The account of the sponsor, if he wants to be known
+            sponsor: Option<Global<Account>>,
+            // A message from the sponsor
+            message: Option<String>,
+        ) {
+            assert!(
+                xrd_bucket.resource_address() == XRD,
+                "Wrong coin"
             );
+            let amount = xrd_bucket.amount();
+            self.check_donation(amount, &sponsor, &message);
 
-            xrd_bucket
+            // Deposit the XRD and distribute them among the holders
+            self.dividends.put(xrd_bucket);
+            self.distribute_dividends(amount);
+
+            self.finalize_donation(DonationTarget::Dividends, amount, sponsor, message);
         }
 
-        // This method lets a user receive his pending dividends and jackpot shares
-        pub fn withdraw_dividends(
+        /* Sponsors can use this method to donate XRD to the next jackpot; the donation raises the
+         * current jackpot amount without buying any 401k
+         */
+        pub fn donate_jackpot(
             &mut self,
-            // The account to receive dividends and jackpot shares for
-            account: Global<Account>,
-        ) -> (
-            // Dividends
-            Bucket,
-            // Jackpot shares
-            Option<Bucket>,
+            // The donated XRD
+            xrd_bucket: Bucket,
+            // The account of the sponsor, if he wants to be known
+            sponsor: Option<Global<Account>>,
+            // A message from the sponsor
+            message: Option<String>,
         ) {
-            // Check that the account owner has actually been involved in this transaction
-            Runtime::assert_access_rule(account.get_owner_role().rule);
+            assert!(
+                xrd_bucket.resource_address() == XRD,
+                "Wrong coin"
+            );
+            let amount = xrd_bucket.amount();
+            self.check_donation(amount, &sponsor, &message);
 
-            // Get information about this account
-            let mut user = self.users.get(&account).expect("Account not found").clone();
+            // Add the XRD to the next jackpot
+            self.jackpot.put(xrd_bucket);
+            self.current_jackpot_amount += amount;
 
-            // Accrew pending dividends to the user
-            self.accrew_dividends(&mut user);
+            self.finalize_donation(DonationTarget::Jackpot, amount, sponsor, message);
+        }
 
-            // Take the pending dividends
-            let dividends_bucket = self.dividends.take(user.accrued_dividends);
+        /* Sponsors can use this method to distribute reward tokens to the current 401k holders
+         * pro rata; the token must have been added by the owner
+         */
+        pub fn donate_rewards(
+            &mut self,
+            // The donated reward tokens
+            reward_bucket: Bucket,
+            // The account of the sponsor, if he wants to be known
+            sponsor: Option<Global<Account>>,
+            // A message from the sponsor
+            message: Option<String>,
+        ) {
+            let resource_address = reward_bucket.resource_address();
+            let amount = reward_bucket.amount();
+            self.check_donation(amount, &sponsor, &message);
 
-            // Get any new or previously accrued jackpot shares
-            let jackpot_bucket = self.check_won_jackpots(&mut user, true);
+            self.distribute_rewards(reward_bucket);
 
-            // Emit the WithdrawDividendsEvent event
-            Runtime::emit_event(
-                WithdrawDividendsEvent {
-                    account: account,
-                    withdrawn_dividends: user.accrued_dividends,
-                    withdrawn_jackpot: match jackpot_bucket {
-                        None => Decimal::ZERO,
-                        Some(ref bucket) => bucket.amount(),
-                    }
-                }
+            self.finalize_donation(DonationTarget::Rewards(resource_address), amount, sponsor, message);
+        }
+
+        // This method adds XRD to the treasury vault to be used for buybacks
+        pub fn deposit_treasury(
+            &mut self,
+            // The XRD to add to the treasury
+            xrd_bucket: Bucket,
+        ) {
+            assert!(
+                xrd_bucket.resource_address() == XRD,
+                "Wrong coin"
             );
 
-            // No more pending dividends for this account
-            user.accrued_dividends = Decimal::ZERO;
+            self.treasury.put(xrd_bucket);
+        }
 
-            // Save updated accout information
-            self.users.insert(
-                account,
-                user,
-            );
+        // The owner can use this method to take XRD out of the treasury vault
+        pub fn withdraw_treasury(
+            &mut self,
+            // Amount of XRD to withdraw
+            amount: Decimal,
+        ) -> Bucket {
+            self.treasury.take(amount)
+        }
 
-            (
-                dividends_bucket,
-                jackpot_bucket,
-            )
+        /* This method buys back 401k with the provided XRD and burns them.
+         * The XRD exceeding the supply cap are returned.
+         */
+        pub fn buyback(
+            &mut self,
+            // The XRD to buy back 401k with
+            xrd_bucket: Bucket,
+            // Whether the dividends and jackpot fees must be skipped
+            skip_fees: bool,
+        ) -> Bucket {
+            self.settle_buyback(xrd_bucket, skip_fees, false)
         }
 
-        pub fn airdrop(
+        /* This method buys back 401k with XRD from the treasury vault and burns them.
+         * The XRD exceeding the supply cap go back to the treasury.
+         */
+        pub fn buyback_from_treasury(
             &mut self,
-            // XRDs to buy 401k coins
-            mut xrd_bucket: Bucket,
-            // List of recipients. The sum of the Decimals mst be 1 or less
-            recipients: IndexMap<Global<Account>, Decimal>,
+            // Amount of XRD to use
+            amount: Decimal,
+            // Whether the dividends and jackpot fees must be skipped
+            skip_fees: bool,
         ) {
-            // Check that the XRD bucket is not empty
-            let xrd_amount = xrd_bucket.amount();
+            let xrd_bucket = self.treasury.take(amount);
+            let refund_bucket = self.settle_buyback(xrd_bucket, skip_fees, true);
+            self.treasury.put(refund_bucket);
+        }
+
+        /* This method escrows XRD to buy 401k for an account when the spot price falls to or
+         * below max_price; keepers execute the orders through the execute_orders method.
+         * It returns a receipt that can be used to cancel the order.
+         */
+        pub fn place_limit_order(
+            &mut self,
+            // The account to send the 401k to
+            account: Global<Account>,
+            // The XRD to escrow
+            xrd_bucket: Bucket,
+            // The order is executed when the spot price is at or below this price
+            max_price: Decimal,
+        ) -> NonFungibleBucket {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Check that input parameters make sense
             assert!(
-                xrd_amount > Decimal::ZERO,
+                xrd_bucket.resource_address() == XRD && xrd_bucket.amount() > Decimal::ZERO,
                 "No XRD provided"
             );
+            assert!(
+                xrd_bucket.amount() >= self.min_buy_xrd_amount,
+                "Less XRD than the minimum buy amount"
+            );
+            assert!(
+                max_price > Decimal::ZERO,
+                "Wrong max_price"
+            );
 
-            // Take the XRD share to use as dividends
-            let dividends_amount = xrd_amount * self.dividends_percentage;
-            self.dividends.put(
-                xrd_bucket.take(dividends_amount)
+            // Save the order and mint its receipt
+            let order_id = self.next_order_id;
+            self.next_order_id += 1;
+            let xrd_amount = xrd_bucket.amount();
+            self.limit_orders.insert(
+                order_id,
+                LimitOrder {
+                    account: account,
+                    xrd: Vault::with_bucket(xrd_bucket),
+                    max_price: max_price,
+                }
+            );
+            let receipt_bucket = self.order_receipt_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(order_id.into()),
+                LimitOrderReceipt {
+                    account: account,
+                    xrd_amount: xrd_amount,
+                    max_price: max_price,
+                }
             );
 
-            // Take the XRD share to add to the jackpot
-            let jackpot_amount = xrd_amount * self.jackpot_percentage;
-            self.jackpot.put(
-                xrd_bucket.take(jackpot_amount)
+            // Emit the LimitOrderPlacedEvent event
+            Runtime::emit_event(
+                LimitOrderPlacedEvent {
+                    order_id: order_id,
+                    account: account,
+                    xrd_amount: xrd_amount,
+                    max_price: max_price,
+                }
             );
-            self.current_jackpot_amount += jackpot_amount;
 
-            // Get informations needed to compute bought amount
-            let (constant_product, mut xrd_in_pool, current_supply) = self.constant_product();
+            receipt_bucket
+        }
 
-            // Deposit the remainig XRDs in the pool
-            let deposited_xrd = xrd_bucket.amount();
-            self.pool.put(xrd_bucket);
-            xrd_in_pool += deposited_xrd;
+        /* Keepers can use this method to execute the buy limit orders whose price has been
+         * reached; the other orders in the list are skipped
+         */
+        pub fn execute_orders(
+            &mut self,
+            // Ids of the orders to execute
+            order_ids: Vec<u64>,
+        ) {
+            // Check that no other invocation to buy or pre_sell methods happended in this
+            // transaction
+            self.check_transaction();
 
-            // Compute the bought 401k amount
-            let bought_amount = self.max_supply - Decimal::try_from(constant_product / xrd_in_pool).unwrap() - current_supply;
+            // Check that trading has started and it's not a presale
+            self.check_trading_started();
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
 
-            // Compute the bought price and update ATH information if needed
-            let price = deposited_xrd / bought_amount;
-            if price > self.ath {
-                self.ath = price;
-                self.below_jackpot_threshold_since = i64::MAX;
-            } else if price > self.ath * self.jackpot_threshold {
-                if self.below_jackpot_threshold_since < i64::MAX {
-                    self.below_jackpot_threshold_since = i64::MAX;
+            for order_id in order_ids.iter() {
+                // Stop when no more 401k can be bought
+                if self.supply_cap_reached() {
+                    break;
                 }
-            } else {
-                // Trigger the jackpot distribution if it's the case
-                self.check_jackpot_trigger(price);
+
+                // Take the escrowed XRD if the order is open and its price has been reached; only
+                // the XRD that keep the buy price at or below the order price and the supply
+                // below the supply cap are used, and the fill is skipped if they are less than the
+                // minimum buy amount
+                let spot_price = self.spot_price();
+                let max_price = match self.limit_orders.get(order_id) {
+                    None => continue,
+                    Some(order) => order.max_price,
+                };
+                let mut max_xrd_amount = self.xrd_for_max_price(max_price);
+                if self.graduation_pool.is_none() {
+                    max_xrd_amount = max_xrd_amount.min(self.xrd_for_supply_cap());
+                }
+                let (account, xrd_bucket) = match self.limit_orders.get_mut(order_id) {
+                    None => continue,
+                    Some(mut order) => {
+                        let xrd_amount = order.xrd.amount().min(max_xrd_amount);
+                        if xrd_amount == Decimal::ZERO ||
+                            xrd_amount < self.min_buy_xrd_amount ||
+                            spot_price > order.max_price {
+                            continue;
+                        }
+                        (order.account, order.xrd.take(xrd_amount))
+                    },
+                };
+                let xrd_amount = xrd_bucket.amount();
+
+                // Buy the 401k for the account; the XRD exceeding the supply cap stay escrowed
+                let (bought_amount, price, refund_bucket) = self.buy_for_account(account, xrd_bucket);
+                let refunded_xrd_amount = refund_bucket.amount();
+                self.limit_orders.get_mut(order_id).unwrap().xrd.put(refund_bucket);
+                let remaining_xrd_amount = self.limit_orders.get(order_id).unwrap().xrd.amount();
+
+                // Emit the LimitOrderExecutedEvent event
+                Runtime::emit_event(
+                    LimitOrderExecutedEvent {
+                        order_id: *order_id,
+                        account: account,
+                        xrd_amount: xrd_amount - refunded_xrd_amount,
+                        bought_amount: bought_amount,
+                        price: price,
+                        remaining_xrd_amount: remaining_xrd_amount,
+                    }
+                );
             }
+        }
 
-            // Mint the bought 401k coins
-            let mut coin_bucket = self.coin_manager.mint(bought_amount);
+        /* This method cancels a buy limit order and burns its receipt.
+         * It returns the XRD still escrowed, if any.
+         */
+        pub fn cancel_limit_order(
+            &mut self,
+            // The receipt of the order
+            receipt_bucket: NonFungibleBucket,
+        ) -> Bucket {
+            // Check that the receipt_bucket really contains exactly one receipt
+            assert!(
+                receipt_bucket.resource_address() == self.order_receipt_manager.address(),
+                "Wrong receipt"
+            );
+            let order_id = Self::receipt_id(&receipt_bucket.non_fungible_local_id());
 
-            // Mint the deposit badge
-            let deposit_badge_bucket = self.deposit_badge_manager.mint_non_fungible(
-                &NonFungibleLocalId::integer(self.next_badge_id.into()),
-                DepositBadge {
-                    bought_amount: bought_amount,
-                    price: price,
-                    dividends_amount: dividends_amount,
+            // Take the escrowed XRD
+            let xrd_bucket = self.limit_orders.get_mut(&order_id).unwrap().xrd.take_all();
+            receipt_bucket.burn();
+
+            // Emit the LimitOrderCancelledEvent event
+            Runtime::emit_event(
+                LimitOrderCancelledEvent {
+                    order_id: order_id,
+                    refunded_xrd_amount: xrd_bucket.amount(),
                 }
             );
 
-            // For each recipient
-            for (account, share) in recipients.iter() {
-                // Compute the amount of 401k for this recipient
-                let amount = bought_amount * *share;
-                if amount == Decimal::ZERO {
-                    continue;
+            xrd_bucket
+        }
+
+        /* This method creates a recurring buy (DCA) subscription: the XRD budget is used to buy
+         * amount_per_buy XRD of 401k for the account every interval seconds, the first buy is due
+         * immediately. Anybody can execute the due buys through the run_dca method.
+         * It returns a receipt that can be used to cancel the subscription.
+         */
+        pub fn subscribe_dca(
+            &mut self,
+            // The account to send the 401k to
+            account: Global<Account>,
+            // The XRD budget
+            xrd_bucket: Bucket,
+            // Amount of XRD to use for each buy, fees included
+            amount_per_buy: Decimal,
+            // Seconds between two buys
+            interval: i64,
+        ) -> NonFungibleBucket {
+            // Check that the account owner has actually been involved in this transaction
+            Runtime::assert_access_rule(account.get_owner_role().rule);
+
+            // Check that input parameters make sense
+            assert!(
+                xrd_bucket.resource_address() == XRD && xrd_bucket.amount() > Decimal::ZERO,
+                "No XRD provided"
+            );
+            assert!(
+                amount_per_buy > Decimal::ZERO && amount_per_buy >= self.min_buy_xrd_amount,
+                "Wrong amount_per_buy"
+            );
+            assert!(
+                interval > 0 && interval <= MAX_DCA_INTERVAL,
+                "Wrong interval"
+            );
+
+            // Save the subscription and mint its receipt
+            let subscription_id = self.next_subscription_id;
+            self.next_subscription_id += 1;
+            let xrd_amount = xrd_bucket.amount();
+            self.dca_subscriptions.insert(
+                subscription_id,
+                DcaSubscription {
+                    account: account,
+                    xrd: Vault::with_bucket(xrd_bucket),
+                    amount_per_buy: amount_per_buy,
+                    interval: interval,
+                    next_buy_at: Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch,
+                    cancelled: false,
+                }
+            );
+            let receipt_bucket = self.dca_receipt_manager.mint_non_fungible(
+                &NonFungibleLocalId::integer(subscription_id.into()),
+                DcaReceipt {
+                    account: account,
+                    amount_per_buy: amount_per_buy,
+                    interval: interval,
+                }
+            );
+
+            // Emit the DcaSubscribedEvent event
+            Runtime::emit_event(
+                DcaSubscribedEvent {
+                    subscription_id: subscription_id,
+                    account: account,
+                    xrd_amount: xrd_amount,
+                    amount_per_buy: amount_per_buy,
+                    interval: interval,
+                }
+            );
+
+            receipt_bucket
+        }
+
+        // This method adds XRD to the budget of a recurring buy (DCA) subscription
+        pub fn top_up_dca(
+            &mut self,
+            // Id of the subscription
+            subscription_id: u64,
+            // The XRD to add
+            xrd_bucket: Bucket,
+        ) {
+            assert!(
+                xrd_bucket.resource_address() == XRD && xrd_bucket.amount() > Decimal::ZERO,
+                "No XRD provided"
+            );
+
+            let mut subscription = self.dca_subscriptions.get_mut(&subscription_id)
+                .expect("Subscription not found");
+            assert!(
+                !subscription.cancelled,
+                "Subscription cancelled"
+            );
+            let xrd_amount = xrd_bucket.amount();
+            subscription.xrd.put(xrd_bucket);
+
+            // Emit the DcaToppedUpEvent event
+            Runtime::emit_event(
+                DcaToppedUpEvent {
+                    subscription_id: subscription_id,
+                    xrd_amount: xrd_amount,
+                    remaining_xrd_amount: subscription.xrd.amount(),
+                }
+            );
+        }
+
+        /* Anybody can use this method to execute the due buys of recurring buy (DCA)
+         * subscriptions, they can only buy for the subscribed account at its schedule; the
+         * subscriptions in the list that are not due are skipped.
+         * Missed buys are not recovered: the next buy is due at the first interval after now.
+         */
+        pub fn run_dca(
+            &mut self,
+            // Ids of the subscriptions to execute
+            subscription_ids: Vec<u64>,
+        ) {
+            // Check that no other invocation to buy or pre_sell methods happended in this
+            // transaction
+            self.check_transaction();
+
+            // Check that trading has started and it's not a presale
+            self.check_trading_started();
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            let now = Clock::current_time_rounded_to_seconds().seconds_since_unix_epoch;
+            for subscription_id in subscription_ids.iter() {
+                // Stop when no more 401k can be bought
+                if self.supply_cap_reached() {
+                    break;
                 }
 
-                // Is the recipient already registered?
-                let mut recipient = match self.users.get(&account) {
-                    // If not create a new one
-                    None => User {
-                        current_bought_amount: Decimal::ZERO,
-                        dividends_per_401k: self.dividends_per_401k,
-                        accrued_dividends: Decimal::ZERO,
-                        current_jackpot_number: self.current_jackpot_number,
-                        accrued_jackpot: Decimal::ZERO,
+                // Take the XRD for this buy if it's due and schedule the next one
+                let (account, xrd_bucket, next_buy_at) = match self.dca_subscriptions.get_mut(subscription_id) {
+                    None => continue,
+                    Some(mut subscription) => {
+                        let xrd_amount = subscription.amount_per_buy.min(subscription.xrd.amount());
+                        if subscription.cancelled ||
+                            subscription.next_buy_at > now ||
+                            xrd_amount == Decimal::ZERO ||
+                            xrd_amount < self.min_buy_xrd_amount {
+                            continue;
+                        }
+                        subscription.next_buy_at +=
+                            ((now - subscription.next_buy_at) / subscription.interval + 1) * subscription.interval;
+                        (subscription.account, subscription.xrd.take(xrd_amount), subscription.next_buy_at)
                     },
-                    Some(recipient) => recipient.clone(),
                 };
+                let xrd_amount = xrd_bucket.amount();
 
-                // Accrew eventual past dividends and jackpots to the recipient
-                self.accrew_dividends(&mut recipient);
-                _ = self.check_won_jackpots(&mut recipient, false);
+                // Buy the 401k for the account; the XRD exceeding the supply cap go back to the
+                // budget
+                let (bought_amount, price, refund_bucket) = self.buy_for_account(account, xrd_bucket);
+                let mut subscription = self.dca_subscriptions.get_mut(subscription_id).unwrap();
+                let refunded_xrd_amount = refund_bucket.amount();
+                subscription.xrd.put(refund_bucket);
 
-                // Try sending the 401k coins to the recipient
-                let refund = deposit_badge_bucket.authorize_with_all(
-                    || {
-                        account.clone().try_deposit_or_refund(
-                            coin_bucket.take(amount).into(),
-                            None,
-                        )
+                // Emit the DcaExecutedEvent event
+                Runtime::emit_event(
+                    DcaExecutedEvent {
+                        subscription_id: *subscription_id,
+                        account: account,
+                        xrd_amount: xrd_amount - refunded_xrd_amount,
+                        bought_amount: bought_amount,
+                        price: price,
+                        remaining_xrd_amount: subscription.xrd.amount(),
+                        next_buy_at: next_buy_at,
                     }
                 );
+            }
+        }
 
-                // If deposit failed, burn the 401k coins
-                if refund.is_some() {
-                    refund.unwrap().burn();
+        /* This method cancels a recurring buy (DCA) subscription and burns its receipt.
+         * It returns the XRD budget not spent yet.
+         */
+        pub fn cancel_dca(
+            &mut self,
+            // The receipt of the subscription
+            receipt_bucket: NonFungibleBucket,
+        ) -> Bucket {
+            // Check that the receipt_bucket really contains exactly one receipt
+            assert!(
+                receipt_bucket.resource_address() == self.dca_receipt_manager.address(),
+                "Wrong receipt"
+            );
+            let subscription_id = Self::receipt_id(&receipt_bucket.non_fungible_local_id());
 
-                } else {
-                    // Update the bought amount for this user
-                    recipient.current_bought_amount += amount;
+            // Take the XRD budget and close the subscription
+            let xrd_bucket = {
+                let mut subscription = self.dca_subscriptions.get_mut(&subscription_id).unwrap();
+                subscription.cancelled = true;
+                subscription.xrd.take_all()
+            };
+            receipt_bucket.burn();
 
-                    // Emit the BuyEvent event
-                    Runtime::emit_event(
-                        BuyEvent {
-                            account: *account,
-                            price: price,
-                            bought_amount: amount,
-                            current_jackpot_amount: self.current_jackpot_amount,
-                            global_dividends_per_401k: self.dividends_per_401k,
-                            ath: self.ath,
-                            buyer_accrued_jackpot: recipient.accrued_jackpot,
-                            buyer_total_accrued_dividends: recipient.accrued_dividends,
-                        }
-                    );
+            // Emit the DcaCancelledEvent event
+            Runtime::emit_event(
+                DcaCancelledEvent {
+                    subscription_id: subscription_id,
+                    refunded_xrd_amount: xrd_bucket.amount(),
                 }
+            );
 
-                // Update saved recipient information
-                self.users.insert(
-                    *account,
-                    recipient,
-                );
-            }
+            xrd_bucket
+        }
 
-            // Burn the deposit badge and get ready for minting a new one
-            deposit_badge_bucket.burn();
-            self.next_badge_id += 1;
+        /* This method rebalances the XRD staked with the validator: it claims the unstaked XRD
+         * that are ready, aligns the staked amounts with the redemption value of the LSUs, starts
+         * unstaking in a single operation the staking yield, the pending payouts and dividends and
+         * the pool and jackpot XRD exceeding the staking percentage, then stakes the pool and
+         * jackpot XRD below the staking percentage.
+         * No unstake starts while MAX_CLAIM_NFTS claim NFTs exist.
+         */
+        pub fn rebalance_stake(&mut self) {
+            let mut validator = self.validator.expect("No validator");
 
-            // Burn the eventual excess 401k coins and compute the additional dividends amount per
-            // 401k coin
-            coin_bucket.burn();
-            self.dividends_per_401k +=
-                dividends_amount / self.coin_manager.total_supply().unwrap();
+            // Claim the unstaked XRD that are ready
+            let claimed_amount = self.claim_unstaked_xrd();
 
-            // Emit the AirdropCompletedEvent event with the new dividends_per_401k value
+            // Harvest the staking yield or take the slashing loss
+            let (harvested_amount, slashed_amount) = self.update_staked_value();
+
+            // Compute the pool and jackpot XRD exceeding the staking percentage
+            let pool_target_amount = self.pool_xrd_amount() * self.staking_percentage;
+            let jackpot_target_amount = self.jackpot_xrd_amount() * self.staking_percentage;
+            let pool_excess_amount = (self.pool_stake.staked - pool_target_amount).max(Decimal::ZERO);
+            let jackpot_excess_amount = (self.jackpot_stake.staked - jackpot_target_amount).max(Decimal::ZERO);
+
+            // Start unstaking them together with the payouts and the dividends
+            let mut unstaked_amount = pool_excess_amount + jackpot_excess_amount +
+                self.payout_stake.staked + self.dividends_stake.staked;
+            if unstaked_amount > Decimal::ZERO && self.can_unstake() {
+                self.unstake_xrd(unstaked_amount);
+                self.pool_stake.staked -= pool_excess_amount;
+                self.pool_stake.unstaking += pool_excess_amount;
+                self.jackpot_stake.staked -= jackpot_excess_amount;
+                self.jackpot_stake.unstaking += jackpot_excess_amount;
+                self.payout_stake.unstaking += self.payout_stake.staked;
+                self.payout_stake.staked = Decimal::ZERO;
+                self.dividends_stake.unstaking += self.dividends_stake.staked;
+                self.dividends_stake.staked = Decimal::ZERO;
+            } else {
+                unstaked_amount = Decimal::ZERO;
+            }
+
+            // Stake the pool and jackpot XRD below the staking percentage
+            let pool_staked_amount = (pool_target_amount - self.pool_stake.staked - self.pool_stake.unstaking)
+                .min(self.pool.amount())
+                .max(Decimal::ZERO);
+            let jackpot_staked_amount = (jackpot_target_amount - self.jackpot_stake.staked - self.jackpot_stake.unstaking)
+                .min(self.jackpot.amount())
+                .max(Decimal::ZERO);
+            let staked_amount = pool_staked_amount + jackpot_staked_amount;
+            if staked_amount > Decimal::ZERO {
+                let mut xrd_bucket = self.pool.take(pool_staked_amount);
+                xrd_bucket.put(
+                    self.jackpot.take(jackpot_staked_amount)
+                );
+                let lsu_bucket = validator.stake(xrd_bucket.as_fungible());
+                match self.lsus {
+                    None => self.lsus = Some(FungibleVault::with_bucket(lsu_bucket)),
+                    Some(ref mut lsus) => lsus.put(lsu_bucket),
+                }
+                self.pool_stake.staked += pool_staked_amount;
+                self.jackpot_stake.staked += jackpot_staked_amount;
+            }
+
+            // Emit the StakingRebalancedEvent event
             Runtime::emit_event(
-                AirdropCompletedEvent {
-                    global_dividends_per_401k: self.dividends_per_401k,
+                StakingRebalancedEvent {
+                    staked_amount: staked_amount,
+                    unstaked_amount: unstaked_amount,
+                    harvested_amount: harvested_amount,
+                    slashed_amount: slashed_amount,
+                    claimed_amount: claimed_amount,
+                    total_staked_amount: self.pool_stake.staked + self.jackpot_stake.staked,
                 }
             );
         }
 
+        /* This method returns a page of the registered accounts with the amount of 401k each of
+         * them owns
+         */
+        pub fn list_users(
+            &self,
+            // Index of the first account to return
+            offset: u64,
+            // Maximum number of accounts to return
+            limit: u64,
+        ) -> Vec<(Global<Account>, Decimal)> {
+            let end = self.holders_count.min(offset.saturating_add(limit));
+
+            let mut users = vec![];
+            for index in offset..end {
+                let account = *self.user_index.get(&index).unwrap();
+                let user = self.users.get(&account).unwrap();
+                users.push((account, user.current_bought_amount));
+            }
+
+            users
+        }
+
+        /* This method returns a page of the history of an account: the operations are returned
+         * in chronological order, starting from the specified sequence number
+         */
+        pub fn get_history(
+            &self,
+            // The account whose history must be returned
+            account: Global<Account>,
+            // Sequence number of the first operation to return
+            from: u64,
+            // Maximum number of operations to return
+            limit: u64,
+        ) -> Vec<HistoryEntry> {
+            let length = match self.history_lengths.get(&account) {
+                None => 0,
+                Some(length) => *length,
+            };
+            let end = length.min(from.saturating_add(limit));
+
+            let mut entries = vec![];
+            for sequence in from..end {
+                entries.push(
+                    self.history.get(&(account, sequence)).unwrap().clone()
+                );
+            }
+
+            entries
+        }
+
+        /* This method reports the dividends, jackpot, payouts and reward token vault balances
+         * against the liabilities
+         */
+        pub fn audit(&self) -> AuditReport {
+            let dividends_vault_amount = self.dividends.amount();
+            let jackpot_vault_amount = self.jackpot_xrd_amount();
+
+            AuditReport {
+                dividends_vault_amount: dividends_vault_amount,
+                dividends_liability: self.dividends_liability,
+                dividends_surplus: dividends_vault_amount - self.dividends_liability,
+                jackpot_vault_amount: jackpot_vault_amount,
+                jackpot_liability: self.jackpot_liability,
+                current_jackpot_amount: self.current_jackpot_amount,
+                jackpot_surplus: jackpot_vault_amount - self.jackpot_liability -
+                    self.current_jackpot_amount,
+                dust_tolerance: self.dust_tolerance,
+                dividends_dust: self.dividends_dust,
+                jackpot_dust: self.jackpot_dust,
+                payouts_vault_amount: self.payouts.amount() + self.payout_stake.staked +
+                    self.payout_stake.unstaking,
+                pending_xrd_liability: self.pending_xrd_liability,
+                reward_tokens: self.reward_token_addresses.iter()
+                    .map(|resource_address| {
+                        let reward_token = self.reward_tokens.get(resource_address).unwrap();
+                        (
+                            *resource_address,
+                            RewardTokenAudit {
+                                vault_amount: reward_token.vault.amount(),
+                                liability: reward_token.liability,
+                                surplus: reward_token.vault.amount() - reward_token.liability,
+                                dust: reward_token.dust,
+                            }
+                        )
+                    })
+                    .collect(),
+            }
+        }
+
         /* The owner can invoke this method to make changes to one of more component settings
          */
         pub fn update_settings(
@@ -1106,8 +6058,8 @@ mod club401k {
                 "Wrong jackpot_percentage"
             );
             assert!(
-                dividends_percentage + jackpot_percentage < Decimal::ONE,
-                "dividends_percentage + jackpot_percentage >= 100%"
+                dividends_percentage + jackpot_percentage + self.anti_sniper_fee < Decimal::ONE,
+                "dividends_percentage + jackpot_percentage + anti_sniper_fee >= 100%"
             );
             assert!(
                 jackpot_threshold > Decimal::ZERO && jackpot_threshold < Decimal::ONE,
@@ -1124,5 +6076,382 @@ mod club401k {
             self.jackpot_threshold = jackpot_threshold;
             self.jackpot_threshold_time = jackpot_threshold_time;
         }
+
+        /* The owner can invoke this method to change the fees charged by the transfer method;
+         * transfers must also be enabled by updating the transferrer role
+         */
+        pub fn update_transfer_settings(
+            &mut self,
+            // Percentage of the transferred 401k to pay as dividends (0-1 range)
+            dividends_percentage: Decimal,
+            // Percentage of the transferred 401k to pay to the jackpot (0-1 range)
+            jackpot_percentage: Decimal,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                dividends_percentage >= Decimal::ZERO,
+                "Wrong dividends_percentage"
+            );
+            assert!(
+                jackpot_percentage >= Decimal::ZERO,
+                "Wrong jackpot_percentage"
+            );
+            assert!(
+                dividends_percentage + jackpot_percentage < Decimal::ONE,
+                "dividends_percentage + jackpot_percentage >= 100%"
+            );
+
+            // Update settings
+            self.transfer_dividends_percentage = dividends_percentage;
+            self.transfer_jackpot_percentage = jackpot_percentage;
+        }
+
+        // The owner can use this method to change the minimum trade sizes and the dust settings
+        pub fn update_trade_limits(
+            &mut self,
+            // Minimum amount of XRD per buy
+            min_buy_xrd_amount: Decimal,
+            // Minimum amount of 401k per sell, unless the whole position is sold
+            min_sell_amount: Decimal,
+            // A position smaller than this is considered dust
+            dust_threshold: Decimal,
+            // Whether the dust positions left by sells are closed
+            close_dust_positions: bool,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                min_buy_xrd_amount >= Decimal::ZERO,
+                "Wrong min_buy_xrd_amount"
+            );
+            assert!(
+                min_sell_amount >= Decimal::ZERO,
+                "Wrong min_sell_amount"
+            );
+            assert!(
+                dust_threshold >= Decimal::ZERO,
+                "Wrong dust_threshold"
+            );
+
+            // Update settings
+            self.min_buy_xrd_amount = min_buy_xrd_amount;
+            self.min_sell_amount = min_sell_amount;
+            self.dust_threshold = dust_threshold;
+            self.close_dust_positions = close_dust_positions;
+        }
+
+        /* The owner can use this method to add accounts to the presale allowlist or to change
+         * their caps; a zero cap removes the account from the allowlist
+         */
+        pub fn update_presale_allowlist(
+            &mut self,
+            // XRD amount (fees included) each account can spend during the presale
+            caps: IndexMap<Global<Account>, Decimal>,
+        ) {
+            for (account, cap) in caps.iter() {
+                // Check that input parameters make sense
+                assert!(
+                    *cap >= Decimal::ZERO,
+                    "Wrong cap"
+                );
+
+                // Update the allowlist
+                match *cap == Decimal::ZERO {
+                    true => {
+                        self.presale_allowlist.remove(account);
+                    },
+                    false => {
+                        self.presale_allowlist.insert(
+                            *account,
+                            *cap,
+                        );
+                    },
+                }
+            }
+        }
+
+        /* The owner can use this method to fund the presale rebates: the presale buyers pay the
+         * bonding curve price and get back the difference with the presale price from this vault
+         */
+        pub fn deposit_presale_rebates(
+            &mut self,
+            // The XRD to add to the presale rebates vault
+            xrd_bucket: Bucket,
+        ) {
+            assert!(
+                xrd_bucket.resource_address() == XRD,
+                "Wrong coin"
+            );
+
+            self.presale_rebates.put(xrd_bucket);
+        }
+
+        // The owner can use this method to take back the unused presale rebates after the presale
+        pub fn withdraw_presale_rebates(&mut self) -> Bucket {
+            assert!(
+                !self.presale_in_progress(),
+                "Presale in progress"
+            );
+
+            self.presale_rebates.take_all()
+        }
+
+        /* The owner can use this method to set the additional jackpot fee charged on buys and
+         * sells right after the trading start; it decays linearly to zero
+         */
+        pub fn update_anti_sniper_fee(
+            &mut self,
+            // Additional percentage of XRD to pay to the jackpot when trading starts (0-1 range)
+            anti_sniper_fee: Decimal,
+            // How many minutes after the trading start the anti-sniper fee lasts
+            anti_sniper_minutes: i64,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                anti_sniper_fee >= Decimal::ZERO &&
+                    self.dividends_percentage + self.jackpot_percentage + anti_sniper_fee < Decimal::ONE,
+                "Wrong anti_sniper_fee"
+            );
+            assert!(
+                anti_sniper_minutes >= 0,
+                "Wrong anti_sniper_minutes"
+            );
+
+            // Update settings
+            self.anti_sniper_fee = anti_sniper_fee;
+            self.anti_sniper_minutes = anti_sniper_minutes;
+        }
+
+        /* The owner can use this method to add a fungible token that sponsors can distribute to
+         * the 401k holders through the donate_rewards method.
+         * Reward tokens can't be removed.
+         */
+        pub fn add_reward_token(
+            &mut self,
+            // The fungible token to distribute
+            resource_address: ResourceAddress,
+        ) {
+            // Check that input parameters make sense
+            let divisibility = match ResourceManager::from(resource_address).resource_type() {
+                ResourceType::Fungible { divisibility } => divisibility,
+                ResourceType::NonFungible { .. } => Runtime::panic("Reward tokens must be fungible".to_string()),
+            };
+            assert!(
+                resource_address != XRD && resource_address != self.coin_manager.address(),
+                "Wrong coin"
+            );
+            assert!(
+                !self.reward_token_addresses.contains(&resource_address),
+                "Reward token already added"
+            );
+            assert!(
+                self.reward_token_addresses.len() < MAX_REWARD_TOKENS,
+                "Too many reward tokens"
+            );
+
+            // Create the vault and the accumulator of the new token
+            self.reward_tokens.insert(
+                resource_address,
+                RewardToken {
+                    vault: Vault::new(resource_address),
+                    reward_per_401k: PreciseDecimal::ZERO,
+                    divisibility: divisibility,
+                    liability: Decimal::ZERO,
+                    dust: PreciseDecimal::ZERO,
+                }
+            );
+            self.reward_token_addresses.push(resource_address);
+        }
+
+        /* The owner can use this method to choose the validator to stake part of the pool and
+         * jackpot XRD with and the maximum percentage to stake.
+         * The validator can't be changed anymore once some XRD have been staked.
+         */
+        pub fn update_staking_settings(
+            &mut self,
+            // The validator to stake with
+            validator: Option<Global<Validator>>,
+            // Maximum percentage of the pool and jackpot XRD to stake (0-1 range)
+            staking_percentage: Decimal,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                staking_percentage >= Decimal::ZERO && staking_percentage < Decimal::ONE,
+                "Wrong staking_percentage"
+            );
+            assert!(
+                validator.is_some() || staking_percentage == Decimal::ZERO,
+                "No validator"
+            );
+            let validator_changed = match (self.validator, validator) {
+                (Some(old_validator), Some(new_validator)) => old_validator.address() != new_validator.address(),
+                (None, None) => false,
+                _ => true,
+            };
+            assert!(
+                !validator_changed || (self.lsus.is_none() && self.claim_nfts.is_none()),
+                "The validator can't be changed after staking started"
+            );
+
+            // Update settings
+            self.validator = validator;
+            self.staking_percentage = staking_percentage;
+        }
+
+        // The owner can use this method to configure the graduation to a TwoResourcePool
+        pub fn update_graduation_settings(
+            &mut self,
+            // The club can graduate when the bonding curve pool contains this amount of XRD (zero
+            // means only when the 401k supply reaches curve_change_supply)
+            graduation_xrd_amount: Decimal,
+            // Percentage of the bonding curve pool XRD moved to the TwoResourcePool at graduation
+            // (0-1 range, zero disables graduation); the rest goes to the treasury
+            graduation_xrd_percentage: Decimal,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                graduation_xrd_amount >= Decimal::ZERO,
+                "Wrong graduation_xrd_amount"
+            );
+            assert!(
+                graduation_xrd_percentage >= Decimal::ZERO &&
+                    graduation_xrd_percentage <= Decimal::ONE,
+                "Wrong graduation_xrd_percentage"
+            );
+
+            // Update settings
+            self.graduation_xrd_amount = graduation_xrd_amount;
+            self.graduation_xrd_percentage = graduation_xrd_percentage;
+        }
+
+        /* The owner can use this method to change the maximum 401k supply that can be reached by
+         * buying
+         */
+        pub fn update_supply_cap(
+            &mut self,
+            // Maximum 401k supply that can be reached by buying
+            supply_cap: Decimal,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                supply_cap > Decimal::ZERO && supply_cap < self.max_supply,
+                "Wrong supply_cap"
+            );
+
+            // Update settings
+            self.supply_cap = supply_cap;
+        }
+
+        /* The owner can use this method to change the 401k supply below which the jackpot is
+         * rolled over to the next cycle instead of being distributed
+         */
+        pub fn update_min_jackpot_supply(
+            &mut self,
+            // Minimum 401k supply to distribute a jackpot
+            min_jackpot_supply: Decimal,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                min_jackpot_supply >= Decimal::ZERO,
+                "Wrong min_jackpot_supply"
+            );
+
+            // Update settings
+            self.min_jackpot_supply = min_jackpot_supply;
+        }
+
+        /* The owner can use this method to change the maximum shortfall of the dividends or
+         * jackpot vault that is tolerated when withdrawing
+         */
+        pub fn update_dust_tolerance(
+            &mut self,
+            // Maximum shortfall tolerated
+            dust_tolerance: Decimal,
+        ) {
+            // Check that input parameters make sense
+            assert!(
+                dust_tolerance >= Decimal::ZERO,
+                "Wrong dust_tolerance"
+            );
+
+            // Update settings
+            self.dust_tolerance = dust_tolerance;
+        }
+
+        /* The owner can use this method to take the dividends and jackpot shares that no holder
+         * can withdraw because of rounding
+         */
+        pub fn sweep_dust(&mut self) -> Bucket {
+            // Only whole Decimal amounts can be taken out of the vaults: round the dust up, so that
+            // no liability is left once every holder has withdrawn, but never above the liability
+            let dividends_amount = Decimal::try_from(
+                self.dividends_dust.checked_round(18, RoundingMode::AwayFromZero).unwrap()
+            ).unwrap().min(self.dividends_liability);
+            let jackpot_amount = Decimal::try_from(
+                self.jackpot_dust.checked_round(18, RoundingMode::AwayFromZero).unwrap()
+            ).unwrap().min(self.jackpot_liability);
+            self.dividends_dust = (self.dividends_dust - dividends_amount).max(PreciseDecimal::ZERO);
+
+            // Take the dust out of the vaults; the jackpot dust is limited to the liquid XRD, the
+            // rest stays dust
+            let jackpot_amount = jackpot_amount.min(self.jackpot.amount());
+            self.jackpot_dust = (self.jackpot_dust - jackpot_amount).max(PreciseDecimal::ZERO);
+            let mut xrd_bucket = self.take_dividends(dividends_amount);
+            let (jackpot_bucket, _) = self.take_jackpot(jackpot_amount);
+            xrd_bucket.put(jackpot_bucket);
+
+            // Emit the DustSweptEvent event
+            Runtime::emit_event(
+                DustSweptEvent {
+                    dividends_amount: dividends_amount,
+                    jackpot_amount: jackpot_amount,
+                }
+            );
+
+            xrd_bucket
+        }
+
+        /* The owner can invoke this method to allow a component (a DEX pool, a DAO treasury...)
+         * to hold 401k
+         */
+        pub fn add_component_holder(
+            &mut self,
+            // The component to allow
+            component_address: ComponentAddress,
+        ) {
+            let component_holder = match self.component_holders.get(&component_address) {
+                None => ComponentHolder {
+                    allowed: true,
+                    holder: self.new_user(),
+                },
+                Some(component_holder) => {
+                    let mut component_holder = component_holder.clone();
+                    component_holder.allowed = true;
+                    component_holder
+                },
+            };
+
+            self.component_holders.insert(
+                component_address,
+                component_holder,
+            );
+        }
+
+        /* The owner can invoke this method to prevent a component from buying more 401k; it can
+         * still sell the ones it holds and withdraw its dividends
+         */
+        pub fn remove_component_holder(
+            &mut self,
+            // The component to disallow
+            component_address: ComponentAddress,
+        ) {
+            let mut component_holder = self.component_holders.get(&component_address)
+                .expect("Component not found").clone();
+            component_holder.allowed = false;
+
+            self.component_holders.insert(
+                component_address,
+                component_holder,
+            );
+        }
     }
 }
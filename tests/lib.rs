@@ -0,0 +1,1436 @@
+use scrypto_test::prelude::*;
+
+// Same encoding as the AuditReport returned by the audit method
+#[derive(ScryptoSbor, Debug)]
+struct AuditReport {
+    dividends_vault_amount: Decimal,
+    dividends_liability: Decimal,
+    dividends_surplus: Decimal,
+    jackpot_vault_amount: Decimal,
+    jackpot_liability: Decimal,
+    current_jackpot_amount: Decimal,
+    jackpot_surplus: Decimal,
+    dust_tolerance: Decimal,
+    dividends_dust: PreciseDecimal,
+    jackpot_dust: PreciseDecimal,
+    payouts_vault_amount: Decimal,
+    pending_xrd_liability: Decimal,
+    reward_tokens: IndexMap<ResourceAddress, RewardTokenAudit>,
+}
+
+// Same encoding as the RewardTokenAudit returned by the audit method
+#[derive(ScryptoSbor, Debug)]
+struct RewardTokenAudit {
+    vault_amount: Decimal,
+    liability: Decimal,
+    surplus: Decimal,
+    dust: PreciseDecimal,
+}
+
+// Same encoding as the LimitOrderExecutedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct LimitOrderExecutedEvent {
+    order_id: u64,
+    account: ComponentAddress,
+    xrd_amount: Decimal,
+    bought_amount: Decimal,
+    price: Decimal,
+    remaining_xrd_amount: Decimal,
+}
+
+// Same encoding as the BuybackEvent event
+#[derive(ScryptoSbor, Debug)]
+struct BuybackEvent {
+    xrd_amount: Decimal,
+    from_treasury: bool,
+    burned_amount: Decimal,
+    price: Decimal,
+    dividends_amount: Decimal,
+    jackpot_amount: Decimal,
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// Same encoding as the DcaExecutedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct DcaExecutedEvent {
+    subscription_id: u64,
+    account: ComponentAddress,
+    xrd_amount: Decimal,
+    bought_amount: Decimal,
+    price: Decimal,
+    remaining_xrd_amount: Decimal,
+    next_buy_at: i64,
+}
+
+// Same encoding as the PresalePrice argument of the new function
+#[derive(ManifestSbor)]
+#[allow(dead_code)]
+enum PresalePrice {
+    Fixed(Decimal),
+    Discount(Decimal),
+}
+
+// Same encoding as the Presale argument of the new function
+#[derive(ManifestSbor)]
+struct Presale {
+    ends_at: i64,
+    price: PresalePrice,
+}
+
+// Same encoding as the GraduatedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct GraduatedEvent {
+    pool_address: ComponentAddress,
+    xrd_amount: Decimal,
+    coin_amount: Decimal,
+    price: Decimal,
+    treasury_xrd_amount: Decimal,
+    pool_units_amount: Decimal,
+}
+
+// Same encoding as the AirdropRecipientFailedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct AirdropRecipientFailedEvent {
+    account: ComponentAddress,
+    share: Decimal,
+    amount: Decimal,
+}
+
+// Same encoding as the AirdropCampaignCreatedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct AirdropCampaignCreatedEvent {
+    campaign_id: u32,
+    price: Decimal,
+    bought_amount: Decimal,
+    expires_at: i64,
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// Same encoding as the JackpotDistributedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct JackpotDistributedEvent {
+    jackpot_amount: Decimal,
+    prize_per_401k: PreciseDecimal,
+}
+
+// Same encoding as the JackpotRolledOverEvent event
+#[derive(ScryptoSbor, Debug)]
+struct JackpotRolledOverEvent {
+    jackpot_amount: Decimal,
+    current_supply: Decimal,
+}
+
+// Same encoding as the PositionMigratedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct PositionMigratedEvent {
+    from_account: ComponentAddress,
+    to_account: ComponentAddress,
+    migrated_amount: Decimal,
+    to_account_amount: Decimal,
+    to_account_total_accrued_dividends: Decimal,
+    to_account_accrued_jackpot: Decimal,
+}
+
+// Same encoding as the SellEvent event
+#[derive(ScryptoSbor, Debug)]
+struct SellEvent {
+    account: ComponentAddress,
+    price: Decimal,
+    sold_amount: Decimal,
+    current_jackpot_amount: Decimal,
+    global_dividends_per_401k: PreciseDecimal,
+    seller_total_accrued_dividends: Decimal,
+    seller_accrued_jackpot: Decimal,
+    realized_pnl: Decimal,
+    seller_total_realized_pnl: Decimal,
+    deferred_xrd_amount: Decimal,
+}
+
+// Same encoding as the SupplyCapReachedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct SupplyCapReachedEvent {
+    supply_cap: Decimal,
+    refunded_xrd_amount: Decimal,
+}
+
+// Same encoding as the AuctionClosedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct AuctionClosedEvent {
+    price: Decimal,
+    bought_amount: Decimal,
+    filled_xrd_amount: Decimal,
+    unfilled_xrd_amount: Decimal,
+    global_dividends_per_401k: PreciseDecimal,
+}
+
+// Same encoding as the AuctionClaimedEvent event
+#[derive(ScryptoSbor, Debug)]
+struct AuctionClaimedEvent {
+    account: ComponentAddress,
+    amount: Decimal,
+    refunded_xrd_amount: Decimal,
+}
+
+// A user of the test ledger
+#[derive(Clone)]
+struct User {
+    public_key: Secp256k1PublicKey,
+    account: ComponentAddress,
+}
+
+// A test ledger with an instantiated 401k Club component
+struct TestEnv {
+    ledger: DefaultLedgerSimulator,
+    owner: User,
+    owner_badge: ResourceAddress,
+    component: ComponentAddress,
+    coin: ResourceAddress,
+    deposit_badge: ResourceAddress,
+    withdraw_badge: ResourceAddress,
+    // Last consensus round set by advance_time
+    round: u64,
+}
+
+impl TestEnv {
+    /* Publishes the package and instantiates the component with the specified presale, presale
+     * rebates funding and trading start; the instantiation receipt is returned too
+     */
+    fn instantiate(
+        presale: ManifestValue,
+        presale_rebates: Option<Decimal>,
+        trading_starts_at: i64,
+    ) -> (DefaultLedgerSimulator, User, ResourceAddress, TransactionReceiptV1) {
+        let mut ledger = LedgerSimulatorBuilder::new().build();
+        let owner = Self::create_user(&mut ledger);
+        let owner_badge = ledger.create_fungible_resource(dec!(1), 0, owner.account);
+        let package_address = ledger.compile_and_publish(this_package!());
+
+        let mut builder = ManifestBuilder::new().lock_fee_from_faucet();
+        if let Some(amount) = presale_rebates {
+            builder = builder
+                .withdraw_from_account(owner.account, XRD, amount)
+                .take_all_from_worktop(XRD, "rebates");
+        }
+        let manifest = builder
+            .call_function_with_name_lookup(package_address, "Club401k", "new", |lookup| {
+                (
+                    owner_badge,
+                    dec!("0.05"),
+                    dec!("0.05"),
+                    dec!("0.000001"),
+                    dec!(1000000000),
+                    dec!("0.5"),
+                    dec!(10),
+                    dec!("0.5"),
+                    3600i64,
+                    presale,
+                    presale_rebates.map(|_| lookup.bucket("rebates")),
+                    trading_starts_at,
+                )
+            })
+            .deposit_batch(owner.account)
+            .build();
+        let receipt = ledger.execute_manifest(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&owner.public_key)],
+        );
+
+        (ledger, owner, owner_badge, receipt)
+    }
+
+    // Publishes the package and instantiates the component with the specified presale
+    fn new_with_presale(presale: ManifestValue, presale_rebates: Option<Decimal>, trading_starts_at: i64) -> Self {
+        let (ledger, owner, owner_badge, receipt) = Self::instantiate(presale, presale_rebates, trading_starts_at);
+        let (component, deposit_badge, withdraw_badge, coin) = receipt
+            .expect_commit_success()
+            .output::<(ComponentAddress, ResourceAddress, ResourceAddress, ResourceAddress)>(1);
+
+        Self {
+            ledger: ledger,
+            owner: owner,
+            owner_badge: owner_badge,
+            component: component,
+            coin: coin,
+            deposit_badge: deposit_badge,
+            withdraw_badge: withdraw_badge,
+            round: 0,
+        }
+    }
+
+    // Publishes the package and instantiates the component without presale
+    fn new() -> Self {
+        Self::new_with_presale(to_manifest_value_and_unwrap!(&None::<()>), None, 0)
+    }
+
+    fn create_user(ledger: &mut DefaultLedgerSimulator) -> User {
+        let (public_key, _, account) = ledger.new_allocated_account();
+
+        User {
+            public_key: public_key,
+            account: account,
+        }
+    }
+
+    fn new_user(&mut self) -> User {
+        Self::create_user(&mut self.ledger)
+    }
+
+    fn execute(&mut self, manifest: TransactionManifestV1, user: &User) -> TransactionReceiptV1 {
+        self.ledger.execute_manifest(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&user.public_key)],
+        )
+    }
+
+    // Calls a method of the component presenting the owner badge
+    fn call_as_owner(&mut self, method_name: &str, arguments: impl ResolvableArguments) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .create_proof_from_account_of_amount(self.owner.account, self.owner_badge, dec!(1))
+            .call_method(self.component, method_name, arguments)
+            .deposit_batch(self.owner.account)
+            .build();
+        let owner = self.owner.clone();
+
+        self.execute(manifest, &owner)
+    }
+
+    // Calls a method of the component on behalf of a user
+    fn call(&mut self, user: &User, method_name: &str, arguments: impl ResolvableArguments) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .call_method(self.component, method_name, arguments)
+            .deposit_batch(user.account)
+            .build();
+
+        self.execute(manifest, user)
+    }
+
+    fn buy(&mut self, user: &User, xrd_amount: Decimal) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .withdraw_from_account(user.account, XRD, xrd_amount)
+            .take_all_from_worktop(XRD, "xrd")
+            .call_method_with_name_lookup(self.component, "buy", |lookup| (lookup.bucket("xrd"),))
+            .take_all_from_worktop(self.coin, "401k")
+            .take_all_from_worktop(self.deposit_badge, "deposit_badge")
+            .create_proof_from_bucket_of_all("deposit_badge", "deposit_badge_proof")
+            .push_to_auth_zone("deposit_badge_proof")
+            .call_method_with_name_lookup(user.account, "deposit", |lookup| (lookup.bucket("401k"),))
+            .pop_from_auth_zone("deposit_badge_proof1")
+            .drop_proof("deposit_badge_proof1")
+            .call_method_with_name_lookup(self.component, "post_buy", |lookup| {
+                (user.account, lookup.bucket("deposit_badge"))
+            })
+            .deposit_batch(user.account)
+            .build();
+
+        self.execute(manifest, user)
+    }
+
+    fn sell(&mut self, user: &User, coin_amount: Decimal) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .call_method(self.component, "pre_sell", manifest_args!())
+            .take_all_from_worktop(self.withdraw_badge, "withdraw_badge")
+            .create_proof_from_bucket_of_all("withdraw_badge", "withdraw_badge_proof")
+            .push_to_auth_zone("withdraw_badge_proof")
+            .withdraw_from_account(user.account, self.coin, coin_amount)
+            .pop_from_auth_zone("withdraw_badge_proof1")
+            .drop_proof("withdraw_badge_proof1")
+            .take_all_from_worktop(self.coin, "401k")
+            .call_method_with_name_lookup(self.component, "sell", |lookup| {
+                (lookup.bucket("401k"), user.account, lookup.bucket("withdraw_badge"))
+            })
+            .deposit_batch(user.account)
+            .build();
+
+        self.execute(manifest, user)
+    }
+
+    // Moves XRD from the owner account to the treasury vault
+    fn deposit_treasury(&mut self, xrd_amount: Decimal) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .create_proof_from_account_of_amount(self.owner.account, self.owner_badge, dec!(1))
+            .withdraw_from_account(self.owner.account, XRD, xrd_amount)
+            .take_all_from_worktop(XRD, "xrd")
+            .call_method_with_name_lookup(self.component, "deposit_treasury", |lookup| (lookup.bucket("xrd"),))
+            .build();
+        let owner = self.owner.clone();
+
+        self.execute(manifest, &owner)
+    }
+
+    // Subscribes a recurring buy for the user with the specified budget, amount per buy and interval
+    fn subscribe_dca(&mut self, user: &User, xrd_amount: Decimal, amount_per_buy: Decimal, interval: i64) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .withdraw_from_account(user.account, XRD, xrd_amount)
+            .take_all_from_worktop(XRD, "xrd")
+            .call_method_with_name_lookup(self.component, "subscribe_dca", |lookup| {
+                (user.account, lookup.bucket("xrd"), amount_per_buy, interval)
+            })
+            .deposit_batch(user.account)
+            .build();
+
+        self.execute(manifest, user)
+    }
+
+    // Buys 401k with the caller XRD and airdrops them to the recipients; the vesting schedule is a
+    // (cliff, duration) tuple
+    fn airdrop(
+        &mut self,
+        caller: &User,
+        xrd_amount: Decimal,
+        recipients: IndexMap<ComponentAddress, Decimal>,
+        vesting: Option<(i64, i64)>,
+    ) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .withdraw_from_account(caller.account, XRD, xrd_amount)
+            .take_all_from_worktop(XRD, "xrd")
+            .call_method_with_name_lookup(self.component, "airdrop", |lookup| {
+                (lookup.bucket("xrd"), recipients, vesting)
+            })
+            .deposit_batch(caller.account)
+            .build();
+
+        self.execute(manifest, caller)
+    }
+
+    // Creates an airdrop campaign with the caller XRD; the vesting schedule is a (cliff, duration)
+    // tuple
+    fn create_airdrop_campaign(
+        &mut self,
+        caller: &User,
+        xrd_amount: Decimal,
+        allocations: IndexMap<ComponentAddress, Decimal>,
+        expires_at: i64,
+        vesting: Option<(i64, i64)>,
+    ) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .withdraw_from_account(caller.account, XRD, xrd_amount)
+            .take_all_from_worktop(XRD, "xrd")
+            .call_method_with_name_lookup(self.component, "create_airdrop_campaign", |lookup| {
+                (lookup.bucket("xrd"), allocations, expires_at, vesting)
+            })
+            .deposit_batch(caller.account)
+            .build();
+
+        self.execute(manifest, caller)
+    }
+
+    // Commits the user XRD to the opening batch auction
+    fn commit_to_auction(&mut self, user: &User, xrd_amount: Decimal) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .withdraw_from_account(user.account, XRD, xrd_amount)
+            .take_all_from_worktop(XRD, "xrd")
+            .call_method_with_name_lookup(self.component, "commit_to_auction", |lookup| {
+                (user.account, lookup.bucket("xrd"))
+            })
+            .build();
+
+        self.execute(manifest, user)
+    }
+
+    // Donates the donor tokens through the specified donate method, without revealing the sponsor
+    fn donate(
+        &mut self,
+        donor: &User,
+        method_name: &str,
+        resource_address: ResourceAddress,
+        amount: Decimal,
+        message: Option<String>,
+    ) -> TransactionReceiptV1 {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .withdraw_from_account(donor.account, resource_address, amount)
+            .take_all_from_worktop(resource_address, "donation")
+            .call_method_with_name_lookup(self.component, method_name, |lookup| {
+                (lookup.bucket("donation"), None::<ComponentAddress>, message)
+            })
+            .deposit_batch(donor.account)
+            .build();
+
+        self.execute(manifest, donor)
+    }
+
+    // Moves the ledger clock forward by the specified number of seconds
+    fn advance_time(&mut self, seconds: i64) {
+        self.round += 1;
+        let timestamp_ms = self.ledger.get_current_proposer_timestamp_ms() + seconds * 1000;
+        self.ledger
+            .advance_to_round_at_timestamp(Round::of(self.round), timestamp_ms)
+            .expect_commit_success();
+    }
+
+    /* Pumps the price with a whale buy, dumps it below the jackpot threshold and waits for the
+     * threshold time, so that the current jackpot is distributed or rolled over; the bonding
+     * curve pool must hold less than 9000 XRD. The receipt of the triggering buy is returned and
+     * the whale leaves with its 401k, dividends and jackpot share.
+     */
+    fn trigger_jackpot(&mut self) -> TransactionReceiptV1 {
+        let whale = self.new_user();
+        self.buy(&whale, dec!(5000)).expect_commit_success();
+        let coin_amount = self.coin_balance(&whale);
+        self.sell(&whale, coin_amount).expect_commit_success();
+        self.buy(&whale, dec!(1)).expect_commit_success();
+        self.advance_time(3601);
+        let receipt = self.buy(&whale, dec!(1));
+        receipt.expect_commit_success();
+
+        let coin_amount = self.coin_balance(&whale);
+        self.sell(&whale, coin_amount).expect_commit_success();
+        self.call(&whale, "withdraw_dividends", manifest_args!(whale.account)).expect_commit_success();
+
+        receipt
+    }
+
+    // Triggers the jackpot and returns the JackpotDistributedEvent event
+    fn distribute_jackpot(&mut self) -> JackpotDistributedEvent {
+        let receipt = self.trigger_jackpot();
+
+        self.event(&receipt, "JackpotDistributedEvent")
+    }
+
+    // Returns a page of the registered accounts with the amount of 401k each of them owns
+    fn list_users(&mut self, offset: u64, limit: u64) -> Vec<(ComponentAddress, Decimal)> {
+        let owner = self.owner.clone();
+
+        self.call(&owner, "list_users", manifest_args!(offset, limit))
+            .expect_commit_success()
+            .output::<Vec<(ComponentAddress, Decimal)>>(1)
+    }
+
+    // Sets whether the user account accepts deposits of any resource
+    fn set_default_deposit_rule(&mut self, user: &User, rule: DefaultDepositRule) {
+        let manifest = ManifestBuilder::new()
+            .lock_fee_from_faucet()
+            .call_method(user.account, "set_default_deposit_rule", manifest_args!(rule))
+            .build();
+
+        self.execute(manifest, user).expect_commit_success();
+    }
+
+    fn audit(&mut self) -> AuditReport {
+        let owner = self.owner.clone();
+
+        self.call(&owner, "audit", manifest_args!())
+            .expect_commit_success()
+            .output::<AuditReport>(1)
+    }
+
+    // Decodes the first event with the specified name emitted by a successful transaction
+    fn event<T: ScryptoDecode>(&self, receipt: &TransactionReceiptV1, name: &str) -> T {
+        let (_, data) = receipt
+            .expect_commit_success()
+            .application_events
+            .iter()
+            .find(|(type_identifier, _)| self.ledger.event_name(type_identifier) == name)
+            .expect("Event not found");
+
+        scrypto_decode(data).unwrap()
+    }
+
+    fn xrd_balance(&mut self, user: &User) -> Decimal {
+        self.ledger.get_component_balance(user.account, XRD)
+    }
+
+    fn coin_balance(&mut self, user: &User) -> Decimal {
+        self.ledger.get_component_balance(user.account, self.coin)
+    }
+}
+
+#[test]
+fn test_sell_with_staked_pool_unstakes_and_pays_after_the_unbonding_delay() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+
+    // Stake 90% of the pool and jackpot XRD with the genesis validator
+    let validator = env.ledger.get_active_validator_with_key(
+        &Secp256k1PrivateKey::from_u64(1).unwrap().public_key()
+    );
+    env.call_as_owner(
+        "update_staking_settings",
+        manifest_args!(Some(validator), dec!("0.9")),
+    )
+    .expect_commit_success();
+
+    env.buy(&user, dec!(1000)).expect_commit_success();
+    env.call(&user, "rebalance_stake", manifest_args!()).expect_commit_success();
+    let audit = env.audit();
+    assert!(audit.jackpot_vault_amount > Decimal::ZERO);
+
+    // Selling everything needs more than the liquid XRD: the sell succeeds and the missing part
+    // becomes pending
+    let xrd_before_sell = env.xrd_balance(&user);
+    let coin_amount = env.coin_balance(&user);
+    env.sell(&user, coin_amount).expect_commit_success();
+    let liquid_amount = env.xrd_balance(&user) - xrd_before_sell;
+    assert!(liquid_amount > Decimal::ZERO);
+    let pending_amount = env.audit().pending_xrd_liability;
+    assert!(pending_amount > Decimal::ZERO);
+
+    // Nothing can be paid before the unstaking completes
+    env.call(&user, "withdraw_dividends", manifest_args!(user.account)).expect_commit_success();
+    assert_eq!(env.audit().pending_xrd_liability, pending_amount);
+
+    // The sell already started unstaking the pending XRD: wait for the unbonding delay
+    let current_epoch = env.ledger.get_current_epoch();
+    env.ledger.set_current_epoch(current_epoch.after(500).unwrap());
+
+    // The pending XRD are paid now, apart from the unstaking rounding within the dust tolerance
+    let xrd_before_withdraw = env.xrd_balance(&user);
+    env.call(&user, "withdraw_dividends", manifest_args!(user.account)).expect_commit_success();
+    let audit = env.audit();
+    assert!(env.xrd_balance(&user) - xrd_before_withdraw + audit.dust_tolerance >= pending_amount);
+    assert_eq!(audit.pending_xrd_liability, Decimal::ZERO);
+}
+
+#[test]
+fn test_limit_order_never_buys_above_max_price() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+    let keeper = env.new_user();
+
+    // The initial price is 0.000001 XRD, escrow more XRD than needed to double it
+    let max_price = dec!("0.000002");
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .withdraw_from_account(user.account, XRD, dec!(5000))
+        .take_all_from_worktop(XRD, "xrd")
+        .call_method_with_name_lookup(env.component, "place_limit_order", |lookup| {
+            (user.account, lookup.bucket("xrd"), max_price)
+        })
+        .deposit_batch(user.account)
+        .build();
+    env.execute(manifest, &user).expect_commit_success();
+
+    // The order is partially filled: the buy price doesn't exceed max_price and the rest of the
+    // XRD stay escrowed
+    let receipt = env.call(&keeper, "execute_orders", manifest_args!(vec![1u64]));
+    let executed: LimitOrderExecutedEvent = env.event(&receipt, "LimitOrderExecutedEvent");
+    assert!(executed.bought_amount > Decimal::ZERO);
+    assert!(executed.price <= max_price);
+    assert!(executed.remaining_xrd_amount > Decimal::ZERO);
+    assert_eq!(executed.xrd_amount + executed.remaining_xrd_amount, dec!(5000));
+    assert_eq!(env.coin_balance(&user), executed.bought_amount);
+
+    // The spot price is now above max_price: the order is skipped
+    let receipt = env.call(&keeper, "execute_orders", manifest_args!(vec![1u64]));
+    assert!(receipt
+        .expect_commit_success()
+        .application_events
+        .iter()
+        .all(|(type_identifier, _)| env.ledger.event_name(type_identifier) != "LimitOrderExecutedEvent"));
+}
+
+#[test]
+fn test_limit_order_skips_fills_below_the_minimum_buy() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+    let keeper = env.new_user();
+
+    // A max price just above the initial 0.000001 XRD allows to spend about 1 XRD only
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .withdraw_from_account(user.account, XRD, dec!(5000))
+        .take_all_from_worktop(XRD, "xrd")
+        .call_method_with_name_lookup(env.component, "place_limit_order", |lookup| {
+            (user.account, lookup.bucket("xrd"), dec!("0.000001001"))
+        })
+        .deposit_batch(user.account)
+        .build();
+    env.execute(manifest, &user).expect_commit_success();
+
+    // With a 10 XRD minimum buy the clamped fill is skipped and nothing is bought
+    env.call_as_owner("update_trade_limits", manifest_args!(dec!(10), Decimal::ZERO, Decimal::ZERO, false))
+        .expect_commit_success();
+    let receipt = env.call(&keeper, "execute_orders", manifest_args!(vec![1u64]));
+    assert!(receipt
+        .expect_commit_success()
+        .application_events
+        .iter()
+        .all(|(type_identifier, _)| env.ledger.event_name(type_identifier) != "LimitOrderExecutedEvent"));
+    assert_eq!(env.coin_balance(&user), Decimal::ZERO);
+
+    // Without the minimum the same fill goes through
+    env.call_as_owner("update_trade_limits", manifest_args!(Decimal::ZERO, Decimal::ZERO, Decimal::ZERO, false))
+        .expect_commit_success();
+    let receipt = env.call(&keeper, "execute_orders", manifest_args!(vec![1u64]));
+    let executed: LimitOrderExecutedEvent = env.event(&receipt, "LimitOrderExecutedEvent");
+    assert!(executed.xrd_amount < dec!(10));
+    assert_eq!(env.coin_balance(&user), executed.bought_amount);
+}
+
+#[test]
+fn test_treasury_buyback_adds_the_jackpot_fee_like_a_buy() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+    env.buy(&user, dec!(1000)).expect_commit_success();
+
+    env.deposit_treasury(dec!(1000)).expect_commit_success();
+
+    // With the fees, the jackpot fee goes to the current jackpot as in a normal buy
+    let before = env.audit();
+    let receipt = env.call_as_owner("buyback_from_treasury", manifest_args!(dec!(500), false));
+    let buyback: BuybackEvent = env.event(&receipt, "BuybackEvent");
+    assert!(buyback.from_treasury);
+    assert!(buyback.burned_amount > Decimal::ZERO);
+    assert!(buyback.jackpot_amount > Decimal::ZERO);
+    assert!(buyback.dividends_amount > Decimal::ZERO);
+    let after = env.audit();
+    assert_eq!(after.current_jackpot_amount, before.current_jackpot_amount + buyback.jackpot_amount);
+    assert_eq!(after.jackpot_liability, before.jackpot_liability);
+    assert!(after.jackpot_surplus >= Decimal::ZERO);
+
+    // Skipping the fees leaves the jackpot unchanged
+    let receipt = env.call_as_owner("buyback_from_treasury", manifest_args!(dec!(500), true));
+    let buyback: BuybackEvent = env.event(&receipt, "BuybackEvent");
+    assert_eq!(buyback.jackpot_amount, Decimal::ZERO);
+    assert_eq!(buyback.dividends_amount, Decimal::ZERO);
+    assert_eq!(env.audit().current_jackpot_amount, after.current_jackpot_amount);
+}
+
+#[test]
+fn test_dca_can_be_run_by_anybody_and_interval_is_bounded() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+    let stranger = env.new_user();
+
+    // An interval longer than one year is rejected
+    env.subscribe_dca(&user, dec!(300), dec!(100), i64::MAX).expect_commit_failure();
+    env.subscribe_dca(&user, dec!(300), dec!(100), 86400).expect_commit_success();
+
+    // Restricting the keeper role doesn't stop the due buys from being executed
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .create_proof_from_account_of_amount(env.owner.account, env.owner_badge, dec!(1))
+        .set_role(env.component, ModuleId::Main, "keeper", rule!(deny_all))
+        .build();
+    let owner = env.owner.clone();
+    env.execute(manifest, &owner).expect_commit_success();
+
+    let receipt = env.call(&stranger, "run_dca", manifest_args!(vec![1u64]));
+    let executed: DcaExecutedEvent = env.event(&receipt, "DcaExecutedEvent");
+    assert_eq!(executed.account, user.account);
+    assert_eq!(executed.xrd_amount, dec!(100));
+    assert_eq!(executed.remaining_xrd_amount, dec!(200));
+    assert_eq!(env.coin_balance(&user), executed.bought_amount);
+    assert_eq!(env.coin_balance(&stranger), Decimal::ZERO);
+
+    // The next buy is not due yet: the subscription is skipped
+    let receipt = env.call(&stranger, "run_dca", manifest_args!(vec![1u64]));
+    assert!(receipt
+        .expect_commit_success()
+        .application_events
+        .iter()
+        .all(|(type_identifier, _)| env.ledger.event_name(type_identifier) != "DcaExecutedEvent"));
+}
+
+#[test]
+fn test_transfer_recipient_earns_dividends_from_the_transfer_fee() {
+    let mut env = TestEnv::new();
+    let sender = env.new_user();
+    let recipient = env.new_user();
+
+    // Enable the transfers with a 10% fee
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .create_proof_from_account_of_amount(env.owner.account, env.owner_badge, dec!(1))
+        .set_role(env.component, ModuleId::Main, "transferrer", rule!(allow_all))
+        .call_method(env.component, "update_transfer_settings", manifest_args!(dec!("0.05"), dec!("0.05")))
+        .build();
+    let owner = env.owner.clone();
+    env.execute(manifest, &owner).expect_commit_success();
+
+    env.buy(&sender, dec!(1000)).expect_commit_success();
+    env.call(&sender, "withdraw_dividends", manifest_args!(sender.account)).expect_commit_success();
+
+    // Transfer half of the 401k; the recipient gets them net of the fee
+    let coin_amount = env.coin_balance(&sender) / 2;
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .call_method(env.component, "pre_sell", manifest_args!())
+        .take_all_from_worktop(env.withdraw_badge, "withdraw_badge")
+        .create_proof_from_bucket_of_all("withdraw_badge", "withdraw_badge_proof")
+        .push_to_auth_zone("withdraw_badge_proof")
+        .withdraw_from_account(sender.account, env.coin, coin_amount)
+        .pop_from_auth_zone("withdraw_badge_proof1")
+        .drop_proof("withdraw_badge_proof1")
+        .take_all_from_worktop(env.coin, "401k")
+        .call_method_with_name_lookup(env.component, "transfer", |lookup| {
+            (sender.account, recipient.account, lookup.bucket("401k"), lookup.bucket("withdraw_badge"))
+        })
+        .build();
+    env.execute(manifest, &sender).expect_commit_success();
+    assert_eq!(env.coin_balance(&recipient), coin_amount * dec!("0.9"));
+
+    // The recipient already holds the transferred 401k when the fee dividends are distributed,
+    // so it gets its share of them
+    let xrd_before_withdraw = env.xrd_balance(&recipient);
+    env.call(&recipient, "withdraw_dividends", manifest_args!(recipient.account)).expect_commit_success();
+    assert!(env.xrd_balance(&recipient) > xrd_before_withdraw);
+    assert!(env.audit().dividends_surplus >= Decimal::ZERO);
+}
+
+// Ends of the presales used in the tests
+const PRESALE_ENDS_AT: i64 = 4_102_444_800;
+
+// Allowlists a user for the presale with the specified XRD cap
+fn allowlist(env: &mut TestEnv, user: &User, cap: Decimal) {
+    let mut caps: IndexMap<ComponentAddress, Decimal> = IndexMap::new();
+    caps.insert(user.account, cap);
+    env.call_as_owner("update_presale_allowlist", manifest_args!(caps))
+        .expect_commit_success();
+}
+
+#[test]
+fn test_presale_requires_funded_rebates_and_trading_start_after_it() {
+    let presale = to_manifest_value_and_unwrap!(&Some(Presale {
+        ends_at: PRESALE_ENDS_AT,
+        price: PresalePrice::Discount(dec!("0.2")),
+    }));
+
+    // The presale rebates must be funded and trading can't start before the presale ends
+    TestEnv::instantiate(presale.clone(), None, PRESALE_ENDS_AT).3.expect_commit_failure();
+    TestEnv::instantiate(presale.clone(), Some(dec!(500)), 0).3.expect_commit_failure();
+    TestEnv::instantiate(presale, Some(dec!(500)), PRESALE_ENDS_AT).3.expect_commit_success();
+}
+
+#[test]
+fn test_presale_buy_is_priced_on_the_curve_and_gets_the_discount_as_rebate() {
+    let mut env = TestEnv::new_with_presale(
+        to_manifest_value_and_unwrap!(&Some(Presale {
+            ends_at: PRESALE_ENDS_AT,
+            price: PresalePrice::Discount(dec!("0.2")),
+        })),
+        Some(dec!(500)),
+        PRESALE_ENDS_AT,
+    );
+    let user = env.new_user();
+
+    // Only allowlisted accounts can buy during the presale
+    env.buy(&user, dec!(500)).expect_commit_failure();
+    allowlist(&mut env, &user, dec!(1000));
+
+    // The buy happens on the bonding curve and 20% of the spent XRD come back as rebate
+    let xrd_before_buy = env.xrd_balance(&user);
+    env.buy(&user, dec!(500)).expect_commit_success();
+    assert_eq!(xrd_before_buy - env.xrd_balance(&user), dec!(400));
+    assert!(env.coin_balance(&user) > Decimal::ZERO);
+
+    // The rebate is not counted in the pool: the audit stays balanced
+    let audit = env.audit();
+    assert!(audit.dividends_surplus >= Decimal::ZERO);
+    assert!(audit.jackpot_surplus >= Decimal::ZERO);
+
+    // The cap is spent in XRD fees included and nothing can be sold during the presale
+    env.buy(&user, dec!(600)).expect_commit_failure();
+    let coin_amount = env.coin_balance(&user);
+    env.sell(&user, coin_amount).expect_commit_failure();
+}
+
+#[test]
+fn test_fixed_price_presale_buy_pays_the_fixed_price_when_the_curve_is_cheaper() {
+    let mut env = TestEnv::new_with_presale(
+        to_manifest_value_and_unwrap!(&Some(Presale {
+            ends_at: PRESALE_ENDS_AT,
+            price: PresalePrice::Fixed(dec!("0.00001")),
+        })),
+        Some(dec!(1)),
+        PRESALE_ENDS_AT,
+    );
+    let user = env.new_user();
+    allowlist(&mut env, &user, dec!(1000));
+
+    // The curve starts at a tenth of the fixed price: the buyer gets no rebate and the 450 XRD
+    // left after the fees buy exactly 45 million 401k
+    let xrd_before_buy = env.xrd_balance(&user);
+    env.buy(&user, dec!(500)).expect_commit_success();
+    assert_eq!(xrd_before_buy - env.xrd_balance(&user), dec!(500));
+    let coin_amount = env.coin_balance(&user);
+    assert!(coin_amount >= dec!(45000000) && coin_amount < dec!("45000000.000001"));
+
+    // The premium stays in the rebates vault until the presale ends
+    env.call_as_owner("withdraw_presale_rebates", manifest_args!()).expect_commit_failure();
+}
+
+#[test]
+fn test_graduation_seeds_the_pool_with_pool_xrd_and_minted_401k() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+    env.buy(&user, dec!(1000)).expect_commit_success();
+
+    // Graduation is disabled by default
+    env.call_as_owner("graduate", manifest_args!()).expect_commit_failure();
+
+    // Graduate moving half of the bonding curve pool XRD once the pool holds 100 XRD
+    env.call_as_owner("update_graduation_settings", manifest_args!(dec!(100), dec!("0.5")))
+        .expect_commit_success();
+    let club_xrd_before = env.ledger.get_component_balance(env.component, XRD);
+    let receipt = env.call_as_owner("graduate", manifest_args!());
+    let graduated: GraduatedEvent = env.event(&receipt, "GraduatedEvent");
+
+    // Half of the pool XRD are paired with 401k minted at the spot price, the other half goes to
+    // the treasury
+    assert!(graduated.xrd_amount > Decimal::ZERO);
+    assert!(graduated.treasury_xrd_amount > Decimal::ZERO);
+    assert_eq!(graduated.coin_amount, graduated.xrd_amount / graduated.price);
+    assert!(graduated.pool_units_amount > Decimal::ZERO);
+    assert_eq!(env.ledger.get_component_balance(graduated.pool_address, XRD), graduated.xrd_amount);
+    assert_eq!(env.ledger.get_component_balance(graduated.pool_address, env.coin), graduated.coin_amount);
+    assert_eq!(
+        env.ledger.get_component_balance(env.component, XRD),
+        club_xrd_before - graduated.xrd_amount
+    );
+
+    // Buys now take the 401k out of the TwoResourcePool instead of minting them
+    let coins_before = env.coin_balance(&user);
+    env.buy(&user, dec!(100)).expect_commit_success();
+    let bought_amount = env.coin_balance(&user) - coins_before;
+    assert!(bought_amount > Decimal::ZERO);
+    assert_eq!(
+        env.ledger.get_component_balance(graduated.pool_address, env.coin),
+        graduated.coin_amount - bought_amount
+    );
+    assert!(env.ledger.get_component_balance(graduated.pool_address, XRD) > graduated.xrd_amount);
+
+    // Sells put them back in the TwoResourcePool
+    env.sell(&user, bought_amount).expect_commit_success();
+    assert_eq!(env.ledger.get_component_balance(graduated.pool_address, env.coin), graduated.coin_amount);
+
+    // The club can graduate only once
+    env.call_as_owner("graduate", manifest_args!()).expect_commit_failure();
+}
+
+#[test]
+fn test_airdrop_splits_by_share_and_refunds_the_unallocated_xrd() {
+    let mut env = TestEnv::new();
+    let sponsor = env.new_user();
+    let alice = env.new_user();
+    let bob = env.new_user();
+
+    // Shares summing to more than 1 are rejected
+    let mut recipients: IndexMap<ComponentAddress, Decimal> = IndexMap::new();
+    recipients.insert(alice.account, dec!("0.6"));
+    recipients.insert(bob.account, dec!("0.6"));
+    env.airdrop(&sponsor, dec!(1000), recipients, None).expect_commit_failure();
+
+    // The XRD of the unallocated quarter go back to the sponsor, the 401k are split by share
+    let mut recipients: IndexMap<ComponentAddress, Decimal> = IndexMap::new();
+    recipients.insert(alice.account, dec!("0.5"));
+    recipients.insert(bob.account, dec!("0.25"));
+    let xrd_before_airdrop = env.xrd_balance(&sponsor);
+    env.airdrop(&sponsor, dec!(1000), recipients, None).expect_commit_success();
+    assert_eq!(xrd_before_airdrop - env.xrd_balance(&sponsor), dec!(750));
+    let alice_amount = env.coin_balance(&alice);
+    let bob_amount = env.coin_balance(&bob);
+    assert!(bob_amount > Decimal::ZERO);
+    assert!((alice_amount - bob_amount * 2).checked_abs().unwrap() < dec!("0.000001"));
+}
+
+#[test]
+fn test_undeliverable_airdrop_is_stored_in_the_locker_and_claimed_later() {
+    let mut env = TestEnv::new();
+    let sponsor = env.new_user();
+    let recipient = env.new_user();
+
+    // Nothing to claim before the airdrop
+    env.call(&recipient, "claim_locked_coins", manifest_args!(recipient.account)).expect_commit_failure();
+
+    // The recipient account refuses the deposit: the 401k go to the AccountLocker
+    env.set_default_deposit_rule(&recipient, DefaultDepositRule::Reject);
+    let mut recipients: IndexMap<ComponentAddress, Decimal> = IndexMap::new();
+    recipients.insert(recipient.account, Decimal::ONE);
+    let receipt = env.airdrop(&sponsor, dec!(1000), recipients, None);
+    let failed: AirdropRecipientFailedEvent = env.event(&receipt, "AirdropRecipientFailedEvent");
+    assert_eq!(failed.account, recipient.account);
+    assert!(failed.amount > Decimal::ZERO);
+    assert_eq!(env.coin_balance(&recipient), Decimal::ZERO);
+
+    // The claim fails as long as the account refuses the deposit, then it delivers the 401k
+    env.call(&recipient, "claim_locked_coins", manifest_args!(recipient.account)).expect_commit_failure();
+    env.set_default_deposit_rule(&recipient, DefaultDepositRule::Accept);
+    env.call(&recipient, "claim_locked_coins", manifest_args!(recipient.account)).expect_commit_success();
+    assert_eq!(env.coin_balance(&recipient), failed.amount);
+}
+
+#[test]
+fn test_airdrop_campaign_buys_the_allocations_and_each_recipient_claims_once() {
+    let mut env = TestEnv::new();
+    let sponsor = env.new_user();
+    let alice = env.new_user();
+    let stranger = env.new_user();
+
+    // The campaign buys exactly the allocated 401k and the sponsor gets the other XRD back
+    let mut allocations: IndexMap<ComponentAddress, Decimal> = IndexMap::new();
+    allocations.insert(alice.account, dec!(1000));
+    let xrd_before_campaign = env.xrd_balance(&sponsor);
+    let receipt = env.create_airdrop_campaign(&sponsor, dec!(10), allocations, PRESALE_ENDS_AT, None);
+    let created: AirdropCampaignCreatedEvent = env.event(&receipt, "AirdropCampaignCreatedEvent");
+    assert_eq!(created.campaign_id, 1);
+    assert!(created.bought_amount >= dec!(1000));
+    assert!(xrd_before_campaign - env.xrd_balance(&sponsor) < dec!(10));
+
+    // Only the allocated account can claim, and only once
+    env.call(&stranger, "claim_airdrop", manifest_args!(stranger.account)).expect_commit_failure();
+    env.call(&alice, "claim_airdrop", manifest_args!(alice.account)).expect_commit_success();
+    assert_eq!(env.coin_balance(&alice), dec!(1000));
+    env.call(&alice, "claim_airdrop", manifest_args!(alice.account)).expect_commit_failure();
+
+    // The campaign can't be closed before it expires
+    env.call_as_owner("close_airdrop_campaign", manifest_args!(1u32)).expect_commit_failure();
+}
+
+#[test]
+fn test_vested_airdrop_can_be_sold_only_after_the_vesting() {
+    let mut env = TestEnv::new();
+    let sponsor = env.new_user();
+    let recipient = env.new_user();
+    let mut recipients: IndexMap<ComponentAddress, Decimal> = IndexMap::new();
+    recipients.insert(recipient.account, Decimal::ONE);
+
+    // A cliff after the end of the vesting is rejected
+    env.airdrop(&sponsor, dec!(1000), recipients.clone(), Some((7200, 3600))).expect_commit_failure();
+
+    // Nothing can be sold before the cliff
+    env.airdrop(&sponsor, dec!(1000), recipients, Some((3600, 7200))).expect_commit_success();
+    let coin_amount = env.coin_balance(&recipient);
+    assert!(coin_amount > Decimal::ZERO);
+    env.sell(&recipient, coin_amount).expect_commit_failure();
+
+    // Everything can be sold once the vesting ends
+    env.advance_time(7200);
+    env.sell(&recipient, coin_amount).expect_commit_success();
+    assert_eq!(env.coin_balance(&recipient), Decimal::ZERO);
+}
+
+#[test]
+fn test_migrate_position_moves_accrued_dividends_and_pending_jackpot() {
+    let mut env = TestEnv::new();
+    let from = env.new_user();
+    let to = env.new_user();
+    env.buy(&from, dec!(1000)).expect_commit_success();
+    let jackpot = env.distribute_jackpot();
+    assert!(jackpot.jackpot_amount > Decimal::ZERO);
+    let coin_amount = env.coin_balance(&from);
+
+    // Move the whole position; the transaction must be signed by the owners of both accounts
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .call_method(env.component, "pre_sell", manifest_args!())
+        .take_all_from_worktop(env.withdraw_badge, "withdraw_badge")
+        .create_proof_from_bucket_of_all("withdraw_badge", "withdraw_badge_proof")
+        .push_to_auth_zone("withdraw_badge_proof")
+        .withdraw_from_account(from.account, env.coin, coin_amount)
+        .pop_from_auth_zone("withdraw_badge_proof1")
+        .drop_proof("withdraw_badge_proof1")
+        .take_all_from_worktop(env.coin, "401k")
+        .call_method_with_name_lookup(env.component, "migrate_position", |lookup| {
+            (from.account, to.account, lookup.bucket("401k"), lookup.bucket("withdraw_badge"))
+        })
+        .build();
+    env.execute(manifest.clone(), &from).expect_commit_failure();
+    let audit_before = env.audit();
+    let receipt = env.ledger.execute_manifest(
+        manifest,
+        vec![
+            NonFungibleGlobalId::from_public_key(&from.public_key),
+            NonFungibleGlobalId::from_public_key(&to.public_key),
+        ],
+    );
+
+    // The destination account got the 401k, the dividends and the pending jackpot share
+    let migrated: PositionMigratedEvent = env.event(&receipt, "PositionMigratedEvent");
+    assert_eq!(migrated.migrated_amount, coin_amount);
+    assert_eq!(migrated.to_account_amount, coin_amount);
+    assert!(migrated.to_account_total_accrued_dividends > Decimal::ZERO);
+    assert!(migrated.to_account_accrued_jackpot > Decimal::ZERO);
+    assert_eq!(env.coin_balance(&from), Decimal::ZERO);
+    assert_eq!(env.coin_balance(&to), coin_amount);
+    let users = env.list_users(0, 100);
+    assert!(users.contains(&(from.account, Decimal::ZERO)));
+    assert!(users.contains(&(to.account, coin_amount)));
+
+    // No XRD moved: the vaults and the liabilities are unchanged
+    let audit_after = env.audit();
+    assert_eq!(audit_after.dividends_vault_amount, audit_before.dividends_vault_amount);
+    assert_eq!(audit_after.dividends_liability, audit_before.dividends_liability);
+    assert_eq!(audit_after.jackpot_vault_amount, audit_before.jackpot_vault_amount);
+    assert_eq!(audit_after.jackpot_liability, audit_before.jackpot_liability);
+
+    // Only the destination account can withdraw them
+    let xrd_before_withdraw = env.xrd_balance(&to);
+    env.call(&to, "withdraw_dividends", manifest_args!(to.account)).expect_commit_success();
+    assert!(
+        env.xrd_balance(&to) - xrd_before_withdraw >=
+            migrated.to_account_total_accrued_dividends + migrated.to_account_accrued_jackpot
+    );
+    let xrd_before_withdraw = env.xrd_balance(&from);
+    env.call(&from, "withdraw_dividends", manifest_args!(from.account));
+    assert_eq!(env.xrd_balance(&from), xrd_before_withdraw);
+}
+
+#[test]
+fn test_component_holders_are_allowlisted_by_the_owner_and_must_call_themselves() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+
+    // Only the owner can allowlist a component
+    env.call(&user, "add_component_holder", manifest_args!(user.account)).expect_commit_failure();
+    env.call_as_owner("add_component_holder", manifest_args!(user.account)).expect_commit_success();
+
+    // A buy registered for an allowlisted component must come from the component itself, not
+    // from a transaction manifest
+    let manifest = ManifestBuilder::new()
+        .lock_fee_from_faucet()
+        .withdraw_from_account(user.account, XRD, dec!(100))
+        .take_all_from_worktop(XRD, "xrd")
+        .call_method_with_name_lookup(env.component, "buy", |lookup| (lookup.bucket("xrd"),))
+        .take_all_from_worktop(env.coin, "401k")
+        .take_all_from_worktop(env.deposit_badge, "deposit_badge")
+        .create_proof_from_bucket_of_all("deposit_badge", "deposit_badge_proof")
+        .push_to_auth_zone("deposit_badge_proof")
+        .call_method_with_name_lookup(user.account, "deposit", |lookup| (lookup.bucket("401k"),))
+        .pop_from_auth_zone("deposit_badge_proof1")
+        .drop_proof("deposit_badge_proof1")
+        .call_method_with_name_lookup(env.component, "post_buy_component", |lookup| {
+            (user.account, lookup.bucket("deposit_badge"))
+        })
+        .deposit_batch(user.account)
+        .build();
+    env.execute(manifest, &user).expect_commit_failure();
+    env.call(&user, "withdraw_component_dividends", manifest_args!(user.account)).expect_commit_failure();
+
+    // Removing an unknown component fails, removing an allowlisted one succeeds
+    env.call_as_owner("remove_component_holder", manifest_args!(env.component)).expect_commit_failure();
+    env.call_as_owner("remove_component_holder", manifest_args!(user.account)).expect_commit_success();
+}
+
+#[test]
+fn test_list_users_pages_the_accounts_in_registration_order() {
+    let mut env = TestEnv::new();
+    let alice = env.new_user();
+    let bob = env.new_user();
+    let carol = env.new_user();
+    for user in [&alice, &bob, &carol] {
+        env.buy(user, dec!(100)).expect_commit_success();
+    }
+
+    // Accounts that sold everything stay listed with zero 401k
+    let bob_amount = env.coin_balance(&bob);
+    env.sell(&bob, bob_amount).expect_commit_success();
+
+    let first_page = env.list_users(0, 2);
+    assert_eq!(first_page.len(), 2);
+    assert_eq!(first_page[0], (alice.account, env.coin_balance(&alice)));
+    assert_eq!(first_page[1], (bob.account, Decimal::ZERO));
+    let second_page = env.list_users(2, 2);
+    assert_eq!(second_page, vec![(carol.account, env.coin_balance(&carol))]);
+
+    // A page past the last account is empty
+    assert!(env.list_users(3, 2).is_empty());
+    assert!(env.list_users(u64::MAX, u64::MAX).is_empty());
+}
+
+#[test]
+fn test_sells_realize_the_pnl_against_the_average_cost() {
+    let mut env = TestEnv::new();
+    let alice = env.new_user();
+    let bob = env.new_user();
+    env.buy(&alice, dec!(100)).expect_commit_success();
+    env.buy(&bob, dec!(1000)).expect_commit_success();
+
+    // Alice bought before the pump: selling half of her 401k realizes a profit
+    let alice_amount = env.coin_balance(&alice);
+    let xrd_before_sell = env.xrd_balance(&alice);
+    let receipt = env.sell(&alice, alice_amount / 2);
+    let first_sell: SellEvent = env.event(&receipt, "SellEvent");
+    assert!(first_sell.realized_pnl > Decimal::ZERO);
+    assert!(first_sell.realized_pnl < env.xrd_balance(&alice) - xrd_before_sell);
+    assert_eq!(first_sell.seller_total_realized_pnl, first_sell.realized_pnl);
+
+    // The total accumulates across the sells
+    let receipt = env.sell(&alice, alice_amount - alice_amount / 2);
+    let second_sell: SellEvent = env.event(&receipt, "SellEvent");
+    assert_eq!(
+        second_sell.seller_total_realized_pnl,
+        first_sell.realized_pnl + second_sell.realized_pnl
+    );
+
+    // Bob bought at the top and pays the fees twice: he realizes a loss
+    let bob_amount = env.coin_balance(&bob);
+    let receipt = env.sell(&bob, bob_amount);
+    let bob_sell: SellEvent = env.event(&receipt, "SellEvent");
+    assert!(bob_sell.realized_pnl < Decimal::ZERO);
+}
+
+#[test]
+fn test_no_liability_is_left_once_every_holder_withdrew() {
+    let mut env = TestEnv::new();
+    let users = [env.new_user(), env.new_user(), env.new_user()];
+
+    // Many trades of uneven sizes leave rounding residues in the accumulators
+    for round in 0..5u32 {
+        for (index, user) in users.iter().enumerate() {
+            env.buy(user, Decimal::from(37 + 11 * index as u32 + 7 * round)).expect_commit_success();
+        }
+        let coin_amount = env.coin_balance(&users[round as usize % 3]);
+        env.sell(&users[round as usize % 3], coin_amount / 3).expect_commit_success();
+    }
+    env.distribute_jackpot();
+    let audit = env.audit();
+    assert!(audit.dividends_liability > Decimal::ZERO);
+    assert!(audit.jackpot_liability > Decimal::ZERO);
+
+    // Everybody sells and withdraws everything: the vaults never fall short
+    for user in users.iter() {
+        let coin_amount = env.coin_balance(user);
+        env.sell(user, coin_amount).expect_commit_success();
+        env.call(user, "withdraw_dividends", manifest_args!(user.account)).expect_commit_success();
+        let audit = env.audit();
+        assert!(audit.dividends_surplus >= Decimal::ZERO);
+        assert!(audit.jackpot_surplus >= Decimal::ZERO);
+    }
+
+    // Only the rounding residue is left: sweeping it clears the liabilities
+    let audit = env.audit();
+    assert!(audit.dividends_liability <= audit.dust_tolerance);
+    assert!(audit.jackpot_liability <= audit.dust_tolerance);
+    env.call_as_owner("sweep_dust", manifest_args!()).expect_commit_success();
+    let audit = env.audit();
+    assert_eq!(audit.dividends_liability, Decimal::ZERO);
+    assert_eq!(audit.jackpot_liability, Decimal::ZERO);
+    assert_eq!(audit.pending_xrd_liability, Decimal::ZERO);
+    assert!(audit.dividends_surplus >= Decimal::ZERO);
+    assert!(audit.jackpot_surplus >= Decimal::ZERO);
+}
+
+#[test]
+fn test_jackpot_rolls_over_below_the_min_supply_and_is_distributed_later() {
+    let mut env = TestEnv::new();
+    let holder = env.new_user();
+    env.buy(&holder, dec!(100)).expect_commit_success();
+
+    // With too few 401k around the jackpot is kept for the next cycle
+    env.call_as_owner("update_min_jackpot_supply", manifest_args!(dec!(1000000000)))
+        .expect_commit_success();
+    let receipt = env.trigger_jackpot();
+    let rolled_over: JackpotRolledOverEvent = env.event(&receipt, "JackpotRolledOverEvent");
+    assert!(rolled_over.jackpot_amount > Decimal::ZERO);
+    assert!(rolled_over.current_supply < dec!(1000000000));
+    let audit = env.audit();
+    assert_eq!(audit.jackpot_liability, Decimal::ZERO);
+    assert!(audit.current_jackpot_amount >= rolled_over.jackpot_amount);
+
+    // Without the minimum the next cycle distributes the jackpot, the rolled over part included
+    env.call_as_owner("update_min_jackpot_supply", manifest_args!(Decimal::ZERO))
+        .expect_commit_success();
+    let jackpot = env.distribute_jackpot();
+    assert!(jackpot.jackpot_amount > rolled_over.jackpot_amount);
+    let xrd_before_withdraw = env.xrd_balance(&holder);
+    env.call(&holder, "withdraw_dividends", manifest_args!(holder.account)).expect_commit_success();
+    assert!(env.xrd_balance(&holder) > xrd_before_withdraw);
+    assert!(env.audit().jackpot_surplus >= Decimal::ZERO);
+}
+
+#[test]
+fn test_buy_over_the_supply_cap_is_partially_filled_and_refunded() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+
+    // The supply cap must stay below the max supply
+    env.call_as_owner("update_supply_cap", manifest_args!(dec!(1000000000)))
+        .expect_commit_failure();
+    env.call_as_owner("update_supply_cap", manifest_args!(dec!(100000000)))
+        .expect_commit_success();
+
+    // About 123.5 XRD, fees included, are enough to reach the supply cap; the rest is refunded
+    let xrd_before_buy = env.xrd_balance(&user);
+    let receipt = env.buy(&user, dec!(1000));
+    let supply_cap_reached: SupplyCapReachedEvent = env.event(&receipt, "SupplyCapReachedEvent");
+    assert_eq!(supply_cap_reached.supply_cap, dec!(100000000));
+    assert!(supply_cap_reached.refunded_xrd_amount > dec!(876) && supply_cap_reached.refunded_xrd_amount < dec!(877));
+    assert_eq!(
+        env.xrd_balance(&user),
+        xrd_before_buy - dec!(1000) + supply_cap_reached.refunded_xrd_amount
+    );
+    let coin_amount = env.coin_balance(&user);
+    assert!(coin_amount <= dec!(100000000) && coin_amount > dec!(99999999));
+
+    // Once the supply cap is reached no more 401k can be bought
+    env.buy(&user, dec!(10)).expect_commit_failure();
+}
+
+#[test]
+fn test_trades_below_the_minimums_are_rejected_and_dust_positions_are_closed() {
+    let mut env = TestEnv::new();
+    let user = env.new_user();
+    env.call_as_owner("update_trade_limits", manifest_args!(dec!(10), dec!(1000), dec!(100), true))
+        .expect_commit_success();
+
+    // Buys below the minimum XRD amount are rejected
+    env.buy(&user, dec!(5)).expect_commit_failure();
+    env.buy(&user, dec!(10)).expect_commit_success();
+
+    // Sells below the minimum 401k amount are rejected, unless they sell the whole position
+    let coin_amount = env.coin_balance(&user);
+    env.sell(&user, dec!(500)).expect_commit_failure();
+
+    // The dust left by a sell is closed: it stays in the account but no longer counts
+    env.sell(&user, coin_amount - dec!(50)).expect_commit_success();
+    assert_eq!(env.coin_balance(&user), dec!(50));
+    assert_eq!(env.list_users(0, 10), vec![(user.account, Decimal::ZERO)]);
+
+    // The whole position can be sold even if it is below the minimum sell amount
+    env.sell(&user, dec!(50)).expect_commit_success();
+    assert_eq!(env.coin_balance(&user), Decimal::ZERO);
+}
+
+#[test]
+fn test_trading_starts_at_the_scheduled_time_with_a_decaying_anti_sniper_fee() {
+    let mut env = TestEnv::new_with_presale(to_manifest_value_and_unwrap!(&None::<()>), None, PRESALE_ENDS_AT);
+    let sniper = env.new_user();
+    let user = env.new_user();
+    env.call_as_owner("update_anti_sniper_fee", manifest_args!(dec!("0.3"), 60i64))
+        .expect_commit_success();
+
+    // No buys before the trading start
+    env.buy(&sniper, dec!(100)).expect_commit_failure();
+
+    // One second after the trading start the jackpot fee is almost 35%
+    let now = env.ledger.get_current_proposer_timestamp_ms() / 1000;
+    env.advance_time(PRESALE_ENDS_AT - now + 1);
+    env.buy(&sniper, dec!(100)).expect_commit_success();
+    let jackpot_amount = env.audit().current_jackpot_amount;
+    assert!(jackpot_amount > dec!(34) && jackpot_amount < dec!(35));
+
+    // Once the anti-sniper fee has decayed only the regular 5% goes to the jackpot
+    env.advance_time(3600);
+    env.buy(&user, dec!(100)).expect_commit_success();
+    assert_eq!(env.audit().current_jackpot_amount, jackpot_amount + dec!(5));
+}
+
+#[test]
+fn test_auction_committers_get_the_same_price_and_share_the_supply_cap_refund() {
+    let mut env = TestEnv::new_with_presale(to_manifest_value_and_unwrap!(&None::<()>), None, PRESALE_ENDS_AT);
+    let small = env.new_user();
+    let big = env.new_user();
+    env.call_as_owner("update_supply_cap", manifest_args!(dec!(100000000)))
+        .expect_commit_success();
+
+    // The commitment window must close before the trading start
+    let now = env.ledger.get_current_proposer_timestamp_ms() / 1000;
+    env.call_as_owner("open_auction", manifest_args!(PRESALE_ENDS_AT + 1))
+        .expect_commit_failure();
+    env.call_as_owner("open_auction", manifest_args!(now + 3600))
+        .expect_commit_success();
+
+    // 400 XRD are committed but only about 123.5 are needed to reach the supply cap
+    let small_xrd_before = env.xrd_balance(&small);
+    let big_xrd_before = env.xrd_balance(&big);
+    env.commit_to_auction(&small, dec!(100)).expect_commit_success();
+    env.commit_to_auction(&big, dec!(300)).expect_commit_success();
+    env.call(&small, "close_auction", manifest_args!()).expect_commit_failure();
+
+    env.advance_time(3600);
+    env.commit_to_auction(&small, dec!(100)).expect_commit_failure();
+    let receipt = env.call(&small, "close_auction", manifest_args!());
+    let closed: AuctionClosedEvent = env.event(&receipt, "AuctionClosedEvent");
+    assert!(closed.unfilled_xrd_amount > dec!(276) && closed.unfilled_xrd_amount < dec!(277));
+    assert_eq!(closed.filled_xrd_amount + closed.unfilled_xrd_amount, dec!(400));
+
+    // Each committer gets its share of the 401k and of the refund, and can claim only once
+    let receipt = env.call(&small, "claim_auction", manifest_args!(small.account));
+    let small_claim: AuctionClaimedEvent = env.event(&receipt, "AuctionClaimedEvent");
+    env.call(&small, "claim_auction", manifest_args!(small.account)).expect_commit_failure();
+    let receipt = env.call(&big, "claim_auction", manifest_args!(big.account));
+    let big_claim: AuctionClaimedEvent = env.event(&receipt, "AuctionClaimedEvent");
+    assert_eq!(small_claim.amount + big_claim.amount, closed.bought_amount);
+    assert_eq!(small_claim.refunded_xrd_amount + big_claim.refunded_xrd_amount, closed.unfilled_xrd_amount);
+    assert_eq!(env.coin_balance(&small), small_claim.amount);
+    assert_eq!(env.coin_balance(&big), big_claim.amount);
+    assert_eq!(env.xrd_balance(&small), small_xrd_before - dec!(100) + small_claim.refunded_xrd_amount);
+    assert_eq!(env.xrd_balance(&big), big_xrd_before - dec!(300) + big_claim.refunded_xrd_amount);
+
+    // Both of them paid the same price and got the same fraction of their XRD back
+    let tolerance = dec!("0.000000000001");
+    let small_price = (dec!(100) - small_claim.refunded_xrd_amount) / small_claim.amount;
+    let big_price = (dec!(300) - big_claim.refunded_xrd_amount) / big_claim.amount;
+    assert!((small_price - big_price).checked_abs().unwrap() < tolerance);
+    assert!((small_claim.refunded_xrd_amount * dec!(3) - big_claim.refunded_xrd_amount).checked_abs().unwrap() < tolerance);
+}
+
+#[test]
+fn test_donations_go_to_the_current_holders_and_the_next_jackpot() {
+    let mut env = TestEnv::new();
+    let donor = env.new_user();
+    let holder = env.new_user();
+
+    // Nothing can be donated while nobody owns 401k
+    env.donate(&donor, "donate_dividends", XRD, dec!(50), None).expect_commit_failure();
+    env.buy(&holder, dec!(100)).expect_commit_success();
+
+    // Empty donations and too long messages are rejected
+    env.donate(&donor, "donate_dividends", XRD, Decimal::ZERO, None).expect_commit_failure();
+    env.donate(&donor, "donate_jackpot", XRD, dec!(30), Some("x".repeat(257))).expect_commit_failure();
+
+    // The donated dividends are all owed to the only holder
+    let dividends_liability = env.audit().dividends_liability;
+    env.donate(&donor, "donate_dividends", XRD, dec!(50), Some("gm".to_string())).expect_commit_success();
+    let dividends_liability = env.audit().dividends_liability - dividends_liability;
+    assert_eq!(dividends_liability, dec!(50));
+
+    // The donated jackpot raises the next jackpot
+    let current_jackpot_amount = env.audit().current_jackpot_amount;
+    env.donate(&donor, "donate_jackpot", XRD, dec!(30), None).expect_commit_success();
+    assert_eq!(env.audit().current_jackpot_amount, current_jackpot_amount + dec!(30));
+
+    // The holder withdraws all of the dividends, the donated ones included
+    let dividends_liability = env.audit().dividends_liability;
+    let xrd_before_withdraw = env.xrd_balance(&holder);
+    env.call(&holder, "withdraw_dividends", manifest_args!(holder.account)).expect_commit_success();
+    let withdrawn_amount = env.xrd_balance(&holder) - xrd_before_withdraw;
+    assert!((withdrawn_amount - dividends_liability).checked_abs().unwrap() < dec!("0.000001"));
+    assert!(withdrawn_amount > dec!(50));
+}
+
+#[test]
+fn test_reward_tokens_are_distributed_pro_rata_and_withdrawn_with_the_dividends() {
+    let mut env = TestEnv::new();
+    let sponsor = env.new_user();
+    let alice = env.new_user();
+    let bob = env.new_user();
+    let reward_token = env.ledger.create_fungible_resource(dec!(1000), 18, sponsor.account);
+    let other_token = env.ledger.create_fungible_resource(dec!(1000), 18, sponsor.account);
+    env.buy(&alice, dec!(100)).expect_commit_success();
+    env.buy(&bob, dec!(300)).expect_commit_success();
+
+    // Only the tokens added by the owner can be donated, XRD and 401k can't be added
+    env.call_as_owner("add_reward_token", manifest_args!(XRD)).expect_commit_failure();
+    env.call_as_owner("add_reward_token", manifest_args!(reward_token)).expect_commit_success();
+    env.call_as_owner("add_reward_token", manifest_args!(reward_token)).expect_commit_failure();
+    env.donate(&sponsor, "donate_rewards", other_token, dec!(100), None).expect_commit_failure();
+    env.donate(&sponsor, "donate_rewards", reward_token, dec!(100), None).expect_commit_success();
+
+    // Each holder gets a share proportional to his 401k
+    env.call(&alice, "withdraw_dividends", manifest_args!(alice.account)).expect_commit_success();
+    env.call(&bob, "withdraw_dividends", manifest_args!(bob.account)).expect_commit_success();
+    let alice_rewards = env.ledger.get_component_balance(alice.account, reward_token);
+    let bob_rewards = env.ledger.get_component_balance(bob.account, reward_token);
+    let alice_share = alice_rewards / dec!(100);
+    let bob_share = bob_rewards / dec!(100);
+    let tolerance = dec!("0.000001");
+    let supply = env.coin_balance(&alice) + env.coin_balance(&bob);
+    assert!((alice_share - env.coin_balance(&alice) / supply).checked_abs().unwrap() < tolerance);
+    assert!((bob_share - env.coin_balance(&bob) / supply).checked_abs().unwrap() < tolerance);
+    assert!(alice_rewards + bob_rewards <= dec!(100) && alice_rewards + bob_rewards > dec!(100) - tolerance);
+
+    // Nothing but rounding dust is owed anymore
+    let reward_liability = env.audit().reward_tokens.get(&reward_token).unwrap().liability;
+    assert!(reward_liability < tolerance);
+}